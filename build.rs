@@ -11,6 +11,7 @@ fn main() {
 
     let functions = Regex::new(r"#\[pyfunction]\s*(?:\w\s+)*?fn\s+([\w0-9]+)").unwrap();
     let structs = Regex::new(r"#\[pyclass]\s*(?:\w\s+)*?(?:struct|enum)\s+([\w0-9]+)").unwrap();
+    let exceptions = Regex::new(r"create_exception!\(\s*\w+\s*,\s*([\w0-9]+)").unwrap();
 
     fs::write(dest_path, format!("#[pymodule]
     fn {name}(_py: Python, m: &PyModule) -> PyResult<()> {{\n")
@@ -24,5 +25,10 @@ fn main() {
             .map(|s| format!(
                 "m.add_class::<{}>()?;\n", &s[1]))
             .collect::<String>()
+        + &exceptions
+            .captures_iter(&source)
+            .map(|e| format!(
+                "m.add(\"{0}\", _py.get_type::<{0}>())?;\n", &e[1]))
+            .collect::<String>()
         + "Ok(())}").unwrap();
 }
\ No newline at end of file
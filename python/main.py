@@ -3,7 +3,6 @@ import tkinter as tk
 from tkinter import colorchooser
 import random
 import argparse
-from json import dumps, loads
 
 
 class Canvas:
@@ -42,12 +41,12 @@ class Canvas:
 
     def __draw(self, event, hold):
         self.draw(event.x, event.y, hold, self.color, self.network)
-        self.network.emit("draw", dumps(
-            {"x": event.x, "y": event.y, "hold": hold, "color": self.color}))
+        emit = self.network.emit_unreliable if hold else self.network.emit
+        emit("draw", {"x": event.x, "y": event.y, "hold": hold, "color": self.color})
 
     def __erase(self, event):
         self.erase(event.x, event.y)
-        self.network.emit("erase", dumps({"x": event.x, "y": event.y}))
+        self.network.emit("erase", {"x": event.x, "y": event.y})
 
     def choose_color(self):
         self.color = colorchooser.askcolor()[1]
@@ -73,12 +72,12 @@ def connection(peer: Peer):
 
     @peer.on("draw")
     def draw(data):
-        x, y, hold, color = loads(data).values()
+        x, y, hold, color = data.values()
         canvas.draw(x, y, hold, color, peer)
 
     @peer.on("erase")
     def erase(data):
-        canvas.erase(**loads(data))
+        canvas.erase(**data)
 
 
 @net.on("disconnect")
@@ -89,10 +88,10 @@ def disconnect(peer):
 if __name__ == "__main__":
     canvas = Canvas(net)
 
-    net.serve()
+    net.serve(discovery=True)
 
     if args.address:
-        ip, port = args.address.split(":")
-        net.connect(ip, int(port))
+        ip, port = args.address.rsplit(":", 1)
+        net.connect(ip.strip("[]"), int(port))
 
     canvas.start()
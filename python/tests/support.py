@@ -0,0 +1,39 @@
+"""
+Helpers for the tests, which run networks over the "memory" transport so several of them fit in one process without sockets.
+
+Copy the built library next to tknetwork.pyi, as build.ps1 does, and run the tests from the repository root:
+    python -m unittest discover -s python/tests -t python
+"""
+import itertools
+import time
+import unittest
+
+import tknetwork
+
+_names = itertools.count()
+
+
+class NetworkTestCase(unittest.TestCase):
+    def network(self, name=None, serve=True, **kwargs):
+        """Create a network with a name of its own unless one is given, closed when the test ends."""
+        name = name or f"{self.id()}-{next(_names)}"
+        net = tknetwork.Network(name, transport="memory", **kwargs)
+        self.addCleanup(net.close)
+        if serve:
+            net.serve()
+        return net
+
+    def connect(self, net, other):
+        return net.connect(other.local_addresses["memory"])
+
+    def wait_for(self, condition, timeout=5.0):
+        """Wait until condition() is true, as events are handled on other threads."""
+        deadline = time.monotonic() + timeout
+        while not condition():
+            if time.monotonic() > deadline:
+                self.fail("Timed out waiting for the condition")
+            time.sleep(0.01)
+
+    def settle(self, seconds=0.2):
+        """Give events that should not arrive the time to do so."""
+        time.sleep(seconds)
@@ -0,0 +1,39 @@
+import tknetwork
+
+from tests.support import NetworkTestCase
+
+
+class CloseTest(NetworkTestCase):
+    def test_disconnect_gives_the_reason_to_both_sides(self):
+        a, b = self.network(), self.network()
+        connected, reasons = [], []
+        b.on("connect")(connected.append)
+        b.on("disconnect")(lambda peer: reasons.append(peer.disconnect_reason))
+        peer = self.connect(a, b)
+        self.wait_for(lambda: connected)
+        peer.disconnect("kicked")
+        self.wait_for(lambda: reasons)
+        self.assertEqual(reasons, ["kicked"])
+        self.assertEqual(peer.disconnect_reason, "kicked")
+        with self.assertRaises(tknetwork.PeerClosed):
+            peer.emit("draw", 1)
+
+    def test_context_manager(self):
+        b = self.network()
+        disconnected = []
+        b.on("disconnect")(disconnected.append)
+        with tknetwork.Network(f"{self.id()}-a", transport="memory") as a:
+            a.serve()
+            self.connect(a, b)
+            name = a.local_addresses["memory"]
+        self.wait_for(lambda: disconnected)
+        self.assertEqual(a.local_addresses, {})
+        with self.assertRaises(tknetwork.ConnectError):
+            b.connect(name, timeout=0.5)
+        a.close()
+
+    def test_closed_network(self):
+        a = self.network()
+        a.close()
+        with self.assertRaises(RuntimeError):
+            a.connect("elsewhere")
@@ -0,0 +1,29 @@
+from tests.support import NetworkTestCase
+
+VALUES = ["text", b"\x00\x04\xff", {"x": 1, "points": [[0, 0], [1, 2.5]]}, [1, "two", None], 3, 0.5, True, None]
+
+
+class CodecTest(NetworkTestCase):
+    def exchange(self, sender, receiver):
+        received = []
+        receiver.on("draw")(received.append)
+        self.connect(sender, receiver)
+        for value in VALUES:
+            sender.emit("draw", value)
+        self.wait_for(lambda: len(received) == len(VALUES))
+        self.assertEqual(received, VALUES)
+
+    def test_codecs(self):
+        for codec in ["json", "msgpack", "cbor"]:
+            with self.subTest(codec=codec):
+                self.exchange(self.network(codec=codec), self.network(codec=codec))
+
+    def test_mixed_codecs(self):
+        self.exchange(self.network(codec="cbor"), self.network(codec="msgpack"))
+        self.exchange(self.network(codec="json"), self.network(codec="cbor"))
+
+    def test_unsupported(self):
+        with self.assertRaises(ValueError):
+            self.network(codec="xml")
+        with self.assertRaises(TypeError):
+            self.network().emit("draw", object())
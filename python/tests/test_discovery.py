@@ -0,0 +1,33 @@
+import tknetwork
+
+from tests.support import NetworkTestCase
+
+
+class DiscoveryTest(NetworkTestCase):
+    def discovering(self, app="tknetwork"):
+        """Serve a network with discovery, giving the addresses it discovered and the peers that connected."""
+        net = tknetwork.Network("127.0.0.1", 0, app=app)
+        self.addCleanup(net.close)
+        discovered, connected = [], []
+        net.on("discovered")(discovered.append)
+        net.on("connect")(connected.append)
+        try:
+            net.serve(discovery=True)
+        except tknetwork.BindError as e:
+            self.skipTest(f"Cannot join the discovery group: {e}")
+        return net, discovered, connected
+
+    def test_networks_of_an_app_find_each_other(self):
+        a, a_discovered, a_connected = self.discovering()
+        b, b_discovered, b_connected = self.discovering()
+        _, other_discovered, other_connected = self.discovering("other")
+        self.wait_for(lambda: a_connected and b_connected and a_discovered and b_discovered)
+        self.assertEqual(a_discovered, [b.local_addresses["tcp"]])
+        self.assertEqual(b_discovered, [a.local_addresses["tcp"]])
+        self.assertEqual(a_connected[0].node_id, b.node_id)
+        self.settle()
+        self.assertEqual((other_discovered, other_connected), ([], []))
+
+    def test_discovery_needs_tcp(self):
+        with self.assertRaises(ValueError):
+            tknetwork.Network("127.0.0.1", 0).serve(tcp=False, discovery=True)
@@ -0,0 +1,35 @@
+import tknetwork
+
+from tests.support import NetworkTestCase
+
+
+class DuplicateTest(NetworkTestCase):
+    def test_one_peer_per_network(self):
+        a, b = self.network(), self.network()
+        connected = {"a": [], "b": []}
+        a.on("connect")(connected["a"].append)
+        b.on("connect")(connected["b"].append)
+        received = []
+        b.on("draw")(received.append)
+
+        first = self.connect(a, b)
+        self.assertEqual(self.connect(b, a).node_id, a.node_id)
+        self.assertIs(self.connect(a, b), first)
+        self.wait_for(lambda: connected["a"] and connected["b"])
+        self.settle()
+        self.assertEqual([peer.node_id for peer in connected["a"]], [b.node_id])
+        self.assertEqual([peer.node_id for peer in connected["b"]], [a.node_id])
+
+        a.emit("draw", 1)
+        self.wait_for(lambda: received)
+        self.settle()
+        self.assertEqual(received, [1])
+
+    def test_node_ids(self):
+        self.assertEqual(self.network(node_id="board-1").node_id, "board-1")
+        self.assertNotEqual(self.network().node_id, self.network().node_id)
+
+    def test_connecting_to_itself_is_rejected(self):
+        a = self.network()
+        with self.assertRaises(tknetwork.NetworkError):
+            self.connect(a, a)
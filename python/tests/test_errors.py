@@ -0,0 +1,29 @@
+import socket
+
+import tknetwork
+
+from tests.support import NetworkTestCase
+
+
+class ErrorTest(NetworkTestCase):
+    def test_hierarchy(self):
+        errors = [tknetwork.ConnectError, tknetwork.ProtocolError, tknetwork.PeerClosed, tknetwork.Timeout, tknetwork.BindError]
+        for error in errors:
+            self.assertTrue(issubclass(error, tknetwork.NetworkError))
+        self.assertTrue(issubclass(tknetwork.NetworkError, OSError))
+
+    def test_connect_errors_are_raised(self):
+        with self.assertRaises(tknetwork.ConnectError):
+            self.network().connect("nobody", timeout=0.5)
+
+    def test_malformed_messages_go_to_the_error_handler(self):
+        net = tknetwork.Network("127.0.0.1", 0)
+        self.addCleanup(net.close)
+        errors = []
+        net.on("error")(errors.append)
+        net.serve(udp=False)
+        with socket.create_connection(net.local_addresses["tcp"]) as client:
+            client.sendall(b"{not json\x04")
+            self.wait_for(lambda: errors)
+            self.assertIsInstance(errors[0], tknetwork.ProtocolError)
+            self.assertEqual(errors[0].peer.address, client.getsockname())
@@ -0,0 +1,66 @@
+from tests.support import NetworkTestCase
+
+
+class HandlerTest(NetworkTestCase):
+    def setUp(self):
+        self.a, self.b = self.network(), self.network()
+        self.peer = self.connect(self.a, self.b)
+
+    def emit(self, event, data):
+        """Emit from a to b, and wait until b handled a marker emitted after it."""
+        done = []
+        self.b.once("done")(done.append)
+        self.a.emit(event, data)
+        self.a.emit("done", True)
+        self.wait_for(lambda: done)
+
+    def test_handlers_run_in_order(self):
+        calls = []
+        self.b.on("draw")(lambda data: calls.append(("first", data)))
+        self.b.on("draw")(lambda data: calls.append(("second", data)))
+        self.emit("draw", 1)
+        self.assertEqual(calls, [("first", 1), ("second", 1)])
+
+    def test_off_and_once(self):
+        calls = []
+        handler = self.b.on("draw")(calls.append)
+        self.b.once("draw")(lambda data: calls.append(("once", data)))
+        self.emit("draw", 1)
+        self.b.off("draw", handler)
+        self.emit("draw", 2)
+        self.assertEqual(calls, [1, ("once", 1)])
+        with self.assertRaises(ValueError):
+            self.b.off("draw", handler)
+
+    def test_wildcard(self):
+        calls = []
+        self.b.on("*")(lambda event, data: calls.append((event, data)))
+        self.emit("draw", 1)
+        self.assertEqual(calls, [("draw", 1), ("done", True)])
+
+    def test_peer_handlers(self):
+        calls = []
+        handler = self.peer.on("reply")(lambda data: calls.append(("on", data)))
+        self.peer.once("reply")(lambda data: calls.append(("once", data)))
+        self.peer.on("*")(lambda event, data: calls.append((event, data)))
+        self.b.emit("reply", 1)
+        self.wait_for(lambda: len(calls) == 3)
+        self.peer.off("reply", handler)
+        self.b.emit("reply", 2)
+        self.wait_for(lambda: len(calls) == 4)
+        self.settle()
+        self.assertEqual(calls, [("on", 1), ("once", 1), ("reply", 1), ("reply", 2)])
+
+    def test_off_with_handler_that_registers_when_compared(self):
+        event = self.b.on("draw")
+
+        class Registers:
+            def __eq__(other, _):
+                event(lambda data: None)
+                return False
+
+            __hash__ = object.__hash__
+
+        event(lambda data: None)
+        with self.assertRaises(ValueError):
+            self.b.off("draw", Registers())
@@ -0,0 +1,42 @@
+import sys
+from unittest import mock
+
+from tests.support import NetworkTestCase
+
+
+def fail(data):
+    raise ValueError(data)
+
+
+class ExcepthookTest(NetworkTestCase):
+    def setUp(self):
+        self.a, self.b = self.network(), self.network()
+        self.received = []
+        self.b.on("draw")(fail)
+        self.b.on("draw")(self.received.append)
+        self.peer = self.connect(self.a, self.b)
+
+    def test_handlers_keep_running(self):
+        raised = []
+        self.b.excepthook = lambda kind, value, traceback: raised.append((kind, str(value)))
+        self.a.emit("draw", "first")
+        self.a.emit("draw", "second")
+        self.wait_for(lambda: len(self.received) == 2)
+        self.assertEqual(self.received, ["first", "second"])
+        self.assertEqual(raised, [(ValueError, "first"), (ValueError, "second")])
+
+    def test_peer_handlers(self):
+        raised = []
+        self.a.excepthook = lambda kind, value, traceback: raised.append(str(value))
+        self.peer.on("reply")(fail)
+        self.b.emit("reply", "from b")
+        self.wait_for(lambda: raised)
+        self.assertEqual(raised, ["from b"])
+
+    def test_sys_excepthook_by_default(self):
+        with mock.patch.object(sys, "excepthook") as hook:
+            self.a.emit("draw", "first")
+            self.wait_for(lambda: self.received)
+            self.wait_for(lambda: hook.called)
+        kind, value, _ = hook.call_args.args
+        self.assertEqual((kind, str(value)), (ValueError, "first"))
@@ -0,0 +1,42 @@
+from tests.support import NetworkTestCase
+
+
+class FloodTest(NetworkTestCase):
+    def mesh(self, count, links):
+        nets = [self.network() for _ in range(count)]
+        self.received = {i: [] for i in range(count)}
+        for i, net in enumerate(nets):
+            net.on("draw")(self.received[i].append)
+        for a, b in links:
+            self.connect(nets[a], nets[b])
+        return nets
+
+    def test_handlers_run_once_per_message(self):
+        # Every network gets the message along two paths.
+        nets = self.mesh(3, [(0, 1), (1, 2), (2, 0)])
+        nets[0].emit("draw", 1, flood=True)
+        nets[0].emit("draw", 2, flood=True)
+        self.wait_for(lambda: len(self.received[1]) == 2 and len(self.received[2]) == 2)
+        self.settle()
+        self.assertEqual(self.received, {0: [], 1: [1, 2], 2: [1, 2]})
+
+    def test_messages_reach_networks_that_are_not_connected(self):
+        nets = self.mesh(4, [(0, 1), (1, 2), (2, 3)])
+        nets[0].emit("draw", "far", flood=True)
+        self.wait_for(lambda: self.received[3])
+        self.settle()
+        self.assertEqual(self.received, {0: [], 1: ["far"], 2: ["far"], 3: ["far"]})
+
+    def test_ttl_limits_hops(self):
+        nets = self.mesh(4, [(0, 1), (1, 2), (2, 3)])
+        nets[0].emit("draw", "near", flood=True, ttl=2)
+        self.wait_for(lambda: self.received[2])
+        self.settle()
+        self.assertEqual(self.received[3], [])
+
+    def test_without_flood_only_peers_receive(self):
+        nets = self.mesh(3, [(0, 1), (1, 2)])
+        nets[0].emit("draw", 1)
+        self.wait_for(lambda: self.received[1])
+        self.settle()
+        self.assertEqual(self.received[2], [])
@@ -0,0 +1,25 @@
+import tknetwork
+
+from tests.support import NetworkTestCase
+
+
+class HandshakeTest(NetworkTestCase):
+    def test_peers_are_described(self):
+        a, b = self.network(app="board"), self.network(app="board")
+        peer = self.connect(a, b)
+        self.assertEqual(peer.app, "board")
+        self.assertRegex(peer.version, r"^\d+\.\d+\.\d+")
+        self.assertEqual(peer.node_id, b.node_id)
+        self.assertIn("members", peer.features)
+
+    def test_other_apps_are_rejected(self):
+        a, b = self.network(app="board"), self.network(app="chat")
+        rejected, connected = [], []
+        b.on("rejected")(lambda peer, reason: rejected.append(reason))
+        b.on("connect")(connected.append)
+        with self.assertRaisesRegex(tknetwork.ProtocolError, "Application mismatch"):
+            self.connect(a, b)
+        self.wait_for(lambda: rejected)
+        self.assertIn("Application mismatch", rejected[0])
+        self.settle()
+        self.assertEqual(connected, [])
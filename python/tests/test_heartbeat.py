@@ -0,0 +1,72 @@
+import os
+import signal
+import subprocess
+import sys
+import time
+import unittest
+
+import tknetwork
+
+from tests.support import NetworkTestCase
+
+# Connects to the port given and then waits, to be stopped by the test.
+PEER = """
+import sys, time, tknetwork
+net = tknetwork.Network("127.0.0.1", 0, heartbeat=0.5, heartbeat_timeout=2)
+net.serve(udp=False)
+net.connect("127.0.0.1", int(sys.argv[1]))
+print(flush=True)
+time.sleep(30)
+"""
+
+
+class HeartbeatTest(NetworkTestCase):
+    def test_idle_peers_stay_connected(self):
+        settings = {"heartbeat": 0.1, "heartbeat_timeout": 0.3}
+        a, b = self.network(**settings), self.network(**settings)
+        disconnected = []
+        a.on("disconnect")(disconnected.append)
+        peer = self.connect(a, b)
+        self.settle(1.0)
+        self.assertEqual(disconnected, [])
+        self.assertIsNone(peer.disconnect_reason)
+
+    def test_invalid_settings(self):
+        for heartbeat, timeout in [(-1, 5), (float("nan"), 5), (1, 1), (1, 0.5)]:
+            with self.subTest(heartbeat=heartbeat, heartbeat_timeout=timeout):
+                with self.assertRaises(ValueError):
+                    self.network(heartbeat=heartbeat, heartbeat_timeout=timeout)
+        self.network(heartbeat=0)
+
+
+@unittest.skipUnless(hasattr(signal, "SIGSTOP"), "stopping the peer needs SIGSTOP")
+class StalledPeerTest(NetworkTestCase):
+    def test_peer_that_stops_reading_times_out(self):
+        net = tknetwork.Network("127.0.0.1", 0, heartbeat=0.5, heartbeat_timeout=2)
+        self.addCleanup(net.close)
+        connected, disconnected = [], []
+        net.on("connect")(connected.append)
+        net.on("disconnect")(lambda peer: disconnected.append(peer.disconnect_reason))
+        net.serve(udp=False)
+
+        env = dict(os.environ, PYTHONPATH=os.path.dirname(tknetwork.__file__))
+        peer = subprocess.Popen(
+            [sys.executable, "-c", PEER, str(net.local_addresses["tcp"][1])],
+            stdout=subprocess.PIPE,
+            env=env,
+        )
+        self.addCleanup(peer.stdout.close)
+        self.addCleanup(peer.wait)
+        self.addCleanup(peer.kill)
+        peer.stdout.readline()
+        self.wait_for(lambda: connected)
+        os.kill(peer.pid, signal.SIGSTOP)
+
+        # More than the socket buffers hold, which used to block emit for good.
+        start = time.monotonic()
+        for _ in range(20):
+            net.emit("big", b"x" * 1_000_000)
+        self.assertLess(time.monotonic() - start, 1.0)
+
+        self.wait_for(lambda: disconnected)
+        self.assertEqual(disconnected, ["timeout"])
@@ -0,0 +1,81 @@
+import json
+import socket
+import struct
+
+import tknetwork
+
+from tests.support import NetworkTestCase
+
+
+class OldClient:
+    """Speaks the old format, 0x04 delimited JSON, as the first whiteboard clients did."""
+
+    def __init__(self, test):
+        self.listener = socket.create_server(("127.0.0.1", 0))
+        self.listener.settimeout(15)
+        test.addCleanup(self.listener.close)
+        self.test = test
+        self.buffer = b""
+
+    @property
+    def port(self):
+        return self.listener.getsockname()[1]
+
+    def accept(self):
+        self.connection, _ = self.listener.accept()
+        self.connection.settimeout(15)
+        self.test.addCleanup(self.connection.close)
+
+    def read(self):
+        while b"\x04" not in self.buffer:
+            data = self.connection.recv(4096)
+            self.test.assertTrue(data, "Connection closed")
+            self.buffer += data
+        frame, self.buffer = self.buffer.split(b"\x04", 1)
+        return frame
+
+    def send(self, event, data):
+        self.connection.sendall(json.dumps({"event": event, "data": data}).encode() + b"\x04")
+
+
+class LegacyTest(NetworkTestCase):
+    def network(self):
+        net = tknetwork.Network("127.0.0.1", 0)
+        self.addCleanup(net.close)
+        net.serve()
+        self.connected, self.received = [], []
+        net.on("connect")(self.connected.append)
+        net.on("chat")(self.received.append)
+        return net
+
+    def exchange(self, net, client):
+        net.emit("draw", {"x": 1})
+        self.assertEqual(json.loads(client.read()), {"event": "draw", "data": '{"x":1}'})
+        client.send("chat", "hi")
+        self.wait_for(lambda: self.received)
+        self.assertEqual(self.received, ["hi"])
+
+    def test_dial_back_after_datagram_request(self):
+        net = self.network()
+        client = OldClient(self)
+        with socket.socket(socket.AF_INET, socket.SOCK_DGRAM) as udp:
+            udp.sendto(struct.pack(">H", client.port), net.local_addresses["udp"][:2])
+        client.accept()
+        self.wait_for(lambda: self.connected)
+        # Nothing but old frames is sent, starting with the first one.
+        net.emit("draw", {"x": 0})
+        self.assertTrue(client.read().startswith(b"{"))
+        self.exchange(net, client)
+
+    def test_fall_back_after_handshake_timeout(self):
+        net = self.network()
+        client = OldClient(self)
+        net.connect("127.0.0.1", client.port, timeout=15)
+        client.accept()
+        self.wait_for(lambda: self.connected)
+        net.emit("draw", {"x": 0})
+        # The handshake was sent before, and is read as frames the old client
+        # cannot parse.
+        while b'"event"' not in client.read():
+            pass
+        self.exchange(net, client)
@@ -0,0 +1,34 @@
+from tests.support import NetworkTestCase
+
+
+class ReconnectTest(NetworkTestCase):
+    def setUp(self):
+        self.a, self.b = self.network(), self.network()
+        self.name = self.b.local_addresses["memory"]
+        self.events = []
+        self.a.on("disconnect")(lambda peer: self.events.append(("disconnect", peer.disconnect_reason)))
+        self.a.on("reconnecting")(lambda peer, attempt: self.events.append(("reconnecting", attempt)))
+        self.a.on("reconnected")(lambda peer: self.events.append(("reconnected", peer)))
+        self.peer = self.connect(self.a, self.b)
+
+    def test_dialed_peers_reconnect(self):
+        received = []
+        self.peer.on("draw")(received.append)
+        self.b.close()
+        # Attempts fail until a network serves on the name again.
+        self.wait_for(lambda: ("reconnecting", 2) in self.events)
+        b = self.network(self.name)
+        self.wait_for(lambda: self.events[-1][0] == "reconnected")
+        self.assertEqual(self.events[0], ("disconnect", "closed"))
+        self.assertIs(self.events[-1][1], self.peer)
+        self.assertIsNone(self.peer.disconnect_reason)
+
+        b.emit("draw", 1)
+        self.wait_for(lambda: received)
+        self.assertEqual(received, [1])
+
+    def test_disconnected_peers_stay_disconnected(self):
+        self.peer.disconnect()
+        self.wait_for(lambda: self.events)
+        self.settle(1.0)
+        self.assertEqual(self.events, [("disconnect", "closed")])
@@ -0,0 +1,29 @@
+from tests.support import NetworkTestCase
+
+
+class RoomTest(NetworkTestCase):
+    def setUp(self):
+        # a is connected to b, which joins the room, and to c, which does not.
+        self.a, self.b, self.c = self.network(), self.network(), self.network()
+        self.received = {"b": [], "c": [], "c-network": []}
+        self.b.room("lobby").on("draw")(self.received["b"].append)
+        self.c.room("lobby").on("draw")(self.received["c"].append)
+        self.c.on("draw")(self.received["c-network"].append)
+        self.to_b = self.connect(self.a, self.b)
+        self.connect(self.a, self.c)
+        self.b.join("lobby")
+        self.wait_for(lambda: self.to_b.rooms == ["lobby"])
+
+    def test_only_members_receive(self):
+        self.a.room("lobby").emit("draw", 1)
+        self.wait_for(lambda: self.received["b"])
+        self.settle()
+        self.assertEqual(self.received, {"b": [1], "c": [], "c-network": []})
+
+    def test_leaving(self):
+        self.b.leave("lobby")
+        self.wait_for(lambda: not self.to_b.rooms)
+        self.a.room("lobby").emit("draw", 1)
+        self.settle()
+        self.assertEqual(self.received["b"], [])
+        self.assertEqual(self.b.rooms, [])
@@ -0,0 +1,42 @@
+import tknetwork
+
+from tests.support import NetworkTestCase
+
+
+class RoutingTest(NetworkTestCase):
+    def setUp(self):
+        # a - b - c, where a only reaches c through b.
+        self.a, self.b, self.c = self.network(), self.network(), self.network()
+        self.received = {name: [] for name in "abc"}
+        for name, net in zip("abc", [self.a, self.b, self.c]):
+            net.on("draw")(self.received[name].append)
+        self.errors = []
+        self.a.on("error")(self.errors.append)
+        self.connect(self.a, self.b)
+        self.connect(self.b, self.c)
+        self.wait_for(lambda: self.c.node_id in self.a.members)
+
+    def test_send_to_relays_along_the_route(self):
+        self.a.send_to(self.c.node_id, "draw", 1)
+        self.a.send_to(self.b.node_id, "draw", 2)
+        self.wait_for(lambda: self.received["c"] and self.received["b"])
+        self.settle()
+        self.assertEqual(self.received, {"a": [], "b": [2], "c": [1]})
+        self.assertEqual(self.errors, [])
+
+    def test_undeliverable_reaches_the_sender(self):
+        disconnected = []
+        self.b.on("disconnect")(disconnected.append)
+        self.c.close()
+        self.wait_for(lambda: disconnected)
+        # a still routes through b, which can no longer deliver it.
+        self.a.send_to(self.c.node_id, "draw", 1)
+        self.wait_for(lambda: self.errors)
+        error = self.errors[0]
+        self.assertIsInstance(error, tknetwork.ConnectError)
+        self.assertEqual((error.node_id, error.event), (self.c.node_id, "draw"))
+
+    def test_unknown_node(self):
+        self.a.send_to("unknown", "draw", 1)
+        self.wait_for(lambda: self.errors)
+        self.assertEqual(self.errors[0].node_id, "unknown")
@@ -0,0 +1,37 @@
+import errno
+import socket
+
+import tknetwork
+
+from tests.support import NetworkTestCase
+
+
+class ServeTest(NetworkTestCase):
+    def test_free_ports_are_reported(self):
+        net = tknetwork.Network("127.0.0.1", 0)
+        self.addCleanup(net.close)
+        self.assertEqual(net.local_addresses, {})
+        net.serve()
+        tcp, udp = net.local_addresses["tcp"], net.local_addresses["udp"]
+        self.assertEqual(tcp[0], "127.0.0.1")
+        self.assertNotEqual(tcp[1], 0)
+        self.assertEqual(udp, tcp)
+
+    def test_port_in_use(self):
+        taken = socket.create_server(("127.0.0.1", 0))
+        self.addCleanup(taken.close)
+        net = tknetwork.Network("127.0.0.1", taken.getsockname()[1])
+        self.addCleanup(net.close)
+        with self.assertRaises(tknetwork.BindError) as raised:
+            net.serve()
+        self.assertEqual(raised.exception.errno, errno.EADDRINUSE)
+        self.assertEqual(net.local_addresses, {})
+
+        # Nothing was served, so serving can be tried again.
+        taken.close()
+        net.serve()
+        self.assertIn("tcp", net.local_addresses)
+
+    def test_memory_transport_serves_no_udp(self):
+        net = self.network("named")
+        self.assertEqual(net.local_addresses, {"memory": "named"})
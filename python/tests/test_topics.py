@@ -0,0 +1,50 @@
+from tests.support import NetworkTestCase
+
+
+class TopicTest(NetworkTestCase):
+    def setUp(self):
+        self.publisher, self.subscriber = self.network(), self.network()
+        self.peer = self.connect(self.publisher, self.subscriber)
+        self.received = []
+
+    def subscribe(self, pattern, name):
+        handler = lambda topic, data: self.received.append((name, topic, data))
+        self.subscriber.subscribe(pattern)(handler)
+        self.wait_for(lambda: pattern in self.peer.subscriptions)
+        return handler
+
+    def test_only_matching_topics_are_sent(self):
+        self.subscribe("chat.*", "chat")
+        self.subscribe("board.1.#", "board")
+        for topic in ["chat.lobby", "chat.lobby.extra", "board.1", "board.1.draw", "board.2"]:
+            self.publisher.publish(topic, topic)
+        self.wait_for(lambda: len(self.received) == 3)
+        self.settle()
+        self.assertEqual(
+            self.received,
+            [("chat", "chat.lobby", "chat.lobby"), ("board", "board.1", "board.1"), ("board", "board.1.draw", "board.1.draw")],
+        )
+
+    def test_subscribing_again_adds_handlers(self):
+        first = self.subscribe("chat.*", "first")
+        self.subscribe("chat.*", "second")
+        self.publisher.publish("chat.lobby", 1)
+        self.wait_for(lambda: len(self.received) == 2)
+        self.assertEqual([name for name, _, _ in self.received], ["first", "second"])
+
+        self.subscriber.unsubscribe("chat.*", first)
+        with self.assertRaises(ValueError):
+            self.subscriber.unsubscribe("chat.*", first)
+        self.publisher.publish("chat.lobby", 2)
+        self.wait_for(lambda: len(self.received) == 3)
+        self.assertEqual(self.received[2], ("second", "chat.lobby", 2))
+        self.assertEqual(self.subscriber.subscriptions, ["chat.*"])
+
+    def test_unsubscribe(self):
+        handler = self.subscribe("chat.*", "chat")
+        self.subscriber.unsubscribe("chat.*", handler)
+        self.wait_for(lambda: not self.peer.subscriptions)
+        self.subscribe("board.#", "board")
+        self.subscriber.unsubscribe("board.#")
+        self.wait_for(lambda: not self.peer.subscriptions)
+        self.assertEqual(self.subscriber.subscriptions, [])
@@ -0,0 +1,39 @@
+import tknetwork
+
+from tests.support import NetworkTestCase
+
+
+class MemoryTransportTest(NetworkTestCase):
+    def test_connect_emit_close(self):
+        a, b = self.network(), self.network()
+        connected, received, disconnected = [], [], []
+        b.on("connect")(connected.append)
+        b.on("draw")(received.append)
+        b.on("disconnect")(lambda peer: disconnected.append(peer.disconnect_reason))
+
+        peer = self.connect(a, b)
+        self.assertEqual(peer.address, b.local_addresses["memory"])
+        self.wait_for(lambda: connected)
+        self.assertEqual(connected[0].node_id, a.node_id)
+
+        a.emit("draw", {"x": 1})
+        peer.emit("draw", b"\x00\x01")
+        self.wait_for(lambda: len(received) == 2)
+        self.assertEqual(received, [{"x": 1}, b"\x00\x01"])
+
+        a.close()
+        self.wait_for(lambda: disconnected)
+        self.assertEqual(disconnected, ["closed"])
+        self.assertEqual(a.local_addresses, {})
+
+    def test_names_are_freed_on_close(self):
+        a, c = self.network(), self.network()
+        name = a.local_addresses["memory"]
+        with self.assertRaises(tknetwork.BindError):
+            self.network(name)
+        a.close()
+        with self.assertRaises(tknetwork.ConnectError):
+            c.connect(name, timeout=0.5)
+
+        b = self.network(name)
+        self.assertEqual(c.connect(name).node_id, b.node_id)
@@ -1,26 +1,199 @@
+from types import TracebackType
+from typing import Any, Callable, Literal, TypedDict, Union
+
+Data = Union[str, bytes, bytearray, memoryview, dict, list, tuple, int, float, bool, None]
+Codec = Literal["json", "msgpack", "cbor"]
+Transport = Literal["tcp", "unix", "memory"]
+
+
+class Service(TypedDict):
+    name: str
+    address: tuple[str, int] | tuple[str, int, int, int]
+    app: str | None
+    version: str | None
+
+
+class Member(TypedDict):
+    addresses: list[tuple[str, int] | tuple[str, int, int, int]]
+    """Addresses the member accepts connections on."""
+    last_seen: float
+    """Time in seconds since the epoch when any network last saw the member connected."""
+    connected: bool
+    """Whether this network is connected to the member."""
+
+
+class NetworkError(OSError):
+    """
+    Base class of the errors of tknetwork.
+
+    Errors raised in the background, for example when a peer sends a malformed message, are passed to the "error" handler of the network. Their peer attribute is the peer they are about, or None.
+    """
+    peer: Peer | None
+
+
+class ConnectError(NetworkError):
+    """
+    Raised by Network.connect when no connection to the peer could be opened.
+
+    Also passed to the "error" handler when an event sent with send_to() could not be delivered, with the node_id and event that were sent and the reason as attributes.
+    """
+    node_id: str
+    event: str
+    reason: str
+
+
+class ProtocolError(NetworkError):
+    """Raised by Network.connect when the peer rejected the connection or failed the handshake, and passed to the "error" handler when a peer sends something malformed."""
+
+
+class PeerClosed(NetworkError):
+    """Raised when emitting to a peer that disconnected."""
+
+
+class Timeout(NetworkError):
+    """Raised by Network.connect when the handshake did not finish in time."""
+
+
+class BindError(NetworkError):
+    """Raised by Network.serve when a socket could not be bound, for example because the port is in use. errno is set when the system gave one."""
+
+
 class Event:
+    """Handlers registered to an event, which run in the order they were registered."""
+    def __call__(func: function) -> function: ...
+
+
+class Once:
+    """Decorator returned by once(), the function runs the next time the event is emitted only."""
     def __call__(func: function) -> function: ...
 
 
 class Peer:
     name: str
+    """Printable address of the peer, such as "192.168.1.2:5000" or "[::1]:5000"."""
+    address: tuple[str, int] | tuple[str, int, int, int] | str
+    """Address of the peer, a (host, port) tuple for IPv4 or (host, port, flowinfo, scope_id) for IPv6 as in the socket module, or the socket path or name for the "unix" and "memory" transports."""
+    node_id: str | None
+    """Node id of the network the peer belongs to, None for peers of older versions."""
+    app: str | None
+    """Application name sent by the peer during the handshake, None for peers using the old format."""
+    version: str | None
+    """tknetwork version of the peer, None for peers using the old format."""
+    features: list[str]
+    """Features the peer announced during the handshake."""
+    certificate: bytes | None
+    """DER encoded TLS certificate of the peer, None when TLS is not used."""
+    fingerprint: str | None
+    """SHA-256 fingerprint of the peer's TLS certificate, None when TLS is not used."""
+    rooms: list[str]
+    """Rooms the peer joined."""
+    subscriptions: list[str]
+    """Topic patterns the peer subscribes to."""
+    disconnect_reason: str | None
+    """Why the peer disconnected: "timeout" when it stopped answering heartbeats or reading what was sent to it, "closed" when it closed the connection or "reset" when the connection failed. When either side calls disconnect() it is the reason given there. None while it is connected."""
 
     def on(self, event: str) -> Event:
         """
         Decorator to register a function to an event.
 
+        Registering several functions to an event runs all of them, in the order they were registered.
+        Functions registered to "*" run for every event emitted by the peer, after the handlers of the event, and receive the name of the event and its data.
+
         Parameters:
             event (str): Name of the event to register to.
         """
         ...
 
-    def emit(self, event: str, data: str):
+    def once(self, event: str) -> Once:
+        """
+        Decorator to register a function that runs the next time the event is emitted only.
+
+        Parameters:
+            event (str): Name of the event to register to.
+        """
+        ...
+
+    def off(self, event: str, handler: Callable[..., object]):
+        """
+        Remove a function registered to an event with on() or once().
+
+        Parameters:
+            event (str): Name of the event the function is registered to.
+            handler (Callable): Function to remove.
+
+        Raises:
+            ValueError: If the function is not registered to the event.
+        """
+        ...
+
+    def emit(self, event: str, data: Data):
         """
         Emit an event to a peer.
 
+        str data is received as str, binary data is received as bytes. Any other data is encoded with the codec negotiated with the peer and received as the decoded Python object.
+
+        Parameters:
+            event (str): Name of the event to emit.
+            data (str | bytes | bytearray | memoryview | dict | list | int | float | bool | None): Data to send to the peer.
+
+        Raises:
+            PeerClosed: If the peer disconnected.
+        """
+        ...
+
+    def emit_unreliable(self, event: str, data: Data):
+        """
+        Emit an event to a peer over UDP.
+
+        The event may be lost, duplicated or arrive out of order, which suits high-frequency events such as mouse motion. Handlers registered with on() receive it like any other event.
+        Falls back to emit() when the peer has no UDP channel, for example when TLS is used or either side serves without UDP.
+
+        Parameters:
+            event (str): Name of the event to emit.
+            data (str | bytes | bytearray | memoryview | dict | list | int | float | bool | None): Data to send to the peer.
+
+        Raises:
+            PeerClosed: If the peer disconnected.
+        """
+        ...
+
+    def disconnect(self, reason: str = "closed"):
+        """
+        Disconnect from the peer.
+
+        The peer is told the reason before the connection closes, and both sides emit "disconnect" with it in disconnect_reason. This network does not dial the peer again, but the peer dials this network again when it connected with connect().
+
+        Parameters:
+            reason (str): Why the peer is disconnected.
+        """
+        ...
+
+
+class Room:
+    """
+    A room of a network, returned by Network.room().
+
+    Events emitted to a room only reach the peers that joined it, and are handled by the handlers registered on the room rather than the network's.
+    """
+    name: str
+    """Name of the room."""
+
+    def on(self, event: str) -> Event:
+        """
+        Decorator to register a function to an event of the room, which runs while the network has joined the room.
+
+        Parameters:
+            event (str): Name of the event to register to.
+        """
+        ...
+
+    def emit(self, event: str, data: Data):
+        """
+        Emit an event to all peers that joined the room. The network does not need to have joined the room itself.
+
         Parameters:
             event (str): Name of the event to emit.
-            data (str): Data to send to the peer.
+            data (str | bytes | bytearray | memoryview | dict | list | int | float | bool | None): Data to send to the peers.
         """
         ...
 
@@ -29,24 +202,79 @@ class Network:
     """
     Class to represent a peer-to-peer network.
 
-    IP should in most cases be 0.0.0.0, which means that the network will be available on all interfaces. Port should be a number between 1024 and 65535.
+    IP should in most cases be 0.0.0.0, which means that the network will be available on all interfaces. Use :: to also accept IPv6, which serves IPv4 and IPv6 on the same port. Port should be a number between 1024 and 65535, or 0 to serve on any free port, found in local_addresses.
 
-    A special event should be registered with @net.on("connect") to handle new connections. The function should take a single parameter, which is the peer that connected.
-    Optionally, an event can be registered with @net.on("disconnect") to handle disconnects. The function should take a single parameter, which is the peer that disconnected.
+    Handlers for the events of the network itself, such as "connect" and "disconnect", are registered with on(). serve() must be called before connecting to other networks, and close() stops the network.
 
     Parameters:
-        ip (str): IP address of the network.
-        port (int): Port of the network.
+        ip (str): IP address of the network, or the socket path or name for the "unix" and "memory" transports.
+        port (int): Port of the network, only used by TCP.
+        legacy (bool): Whether to always send frames in the old 0x04 delimited format. Frames from older clients, which end with a 0x04 byte, are accepted either way, and replies to such peers use that format. Without it, old clients that ask to join over UDP are dialed back in that format, and connections this network opens fall back to it when the other side sends nothing before the handshake times out.
+        codec (str): Codec used to encode objects, one of "json", "msgpack" or "cbor". Each peer is sent data with this codec if it supports it, otherwise with one it does support. JSON has no bytes type, so bytes nested in objects arrive as lists of ints.
+        app (str): Name of the application, only peers with the same name are accepted. It is sent in the handshake peers exchange when they connect, with their protocol version, tknetwork version and supported features, and peers with a different protocol version are rejected as well.
+        tls (bool): Whether to encrypt connections with TLS, using a generated self-signed certificate unless certfile and keyfile are given. Certificates are not checked against any authority, so the application should decide whether to trust a peer from its certificate or fingerprint, for example by comparing it to a known net.fingerprint.
+        certfile (str): Path to a PEM certificate chain, implies tls.
+        keyfile (str): Path to the PEM private key of the certificate.
+        transport (str): How peers connect, one of "tcp", "unix" for networks on the same host, or "memory" for networks in the same process such as in tests. Neither "unix" nor "memory" uses ports or UDP.
+        node_id (str): Node id of the network, random by default. Pass the same id again to keep it across restarts. When two networks end up with several connections to each other, for example because they connected at the same time, all but one are closed before they emit "connect", so there is exactly one peer per network. Connections from a network to itself are rejected.
+        heartbeat (float): Seconds between pings to each peer, 0 to disable heartbeats. Peers using the old format are not pinged.
+        heartbeat_timeout (float): Seconds without receiving anything from a peer after which it is disconnected with the reason "timeout", so connections that silently broke are noticed. Longer than heartbeat. A peer that stops reading what is sent to it is disconnected after as long, and sending to it never blocks meanwhile.
+        reconnect (bool): Whether to dial peers dialed with connect() again after they disconnect, including when the other network closed, unless they were disconnected on this side with disconnect() or close(). Attempts wait twice as long after every failure up to 30 seconds, with a random part so peers that dropped together spread out. The peer keeps its Peer object and the handlers registered on it.
+    """
+    node_id: str
+    """Node id of this network, unique to it among the networks it connects to."""
+    members: dict[str, Member]
     """
-    def __init__(ip: str, port: int): ...
+    Networks known to be part of the mesh by node id, learned from peers, not including this network.
+
+    Peers exchange lists of the networks they know about every few seconds and whenever a peer connects, and connect to members they are not connected to yet, so every network ends up connected to every other. Members that no peer has seen for a minute are forgotten.
+    The lists also tell how many hops away each member is, from which every network keeps a table of the shortest route to each member, used by send_to().
+    """
+    rooms: list[str]
+    """Rooms this network joined."""
+    subscriptions: list[str]
+    """Topic patterns this network subscribes to, in the order they were subscribed."""
+    fingerprint: str | None
+    """SHA-256 fingerprint of this network's TLS certificate, None when TLS is not used."""
+    excepthook: Callable[[type[BaseException], BaseException, TracebackType | None], object] | None
+    """Called like sys.excepthook with exceptions raised by handlers, including handlers registered on peers, and with background failures when there is no "error" handler. sys.excepthook is used when None. Either way the other handlers keep running."""
+    local_addresses: dict[str, tuple[str, int] | tuple[str, int, int, int] | str]
+    """Addresses the network serves on, keyed by "udp" and by the transport of the listener such as "tcp", in the same form as Peer.address. Serving on port 0 picks a free port, which shows up here. Empty before serve() and after close()."""
+
+    def __init__(
+        ip: str,
+        port: int = 0,
+        legacy: bool = False,
+        codec: Codec = "json",
+        app: str = "tknetwork",
+        tls: bool = False,
+        certfile: str | None = None,
+        keyfile: str | None = None,
+        transport: Transport = "tcp",
+        node_id: str | None = None,
+        heartbeat: float = 1.0,
+        heartbeat_timeout: float = 5.0,
+        reconnect: bool = True,
+    ): ...
 
-    def connect(self, ip: str, port: int):
+    def connect(self, ip: str, port: int = 0, timeout: float = 10.0, retries: int = 0) -> Peer:
         """
         Connect to a peer-to-peer network.
 
+        Blocks until the handshake with the peer is done and returns it. The networks then exchange member lists, so this network connects to the rest of the mesh as well.
+        Connections that are refused or time out are retried, a peer that rejects the connection is not. serve() must be called first.
+        When already connected to the network at that address, the existing peer is returned.
+
         Parameters:
-            ip (str): IP address of a peer in the network.
-            port (int): Port of the respective peer in the network.
+            ip (str): IP address or host name of a peer in the network, IPv6 addresses without brackets, or its socket path or name for the "unix" and "memory" transports.
+            port (int): Port of the respective peer in the network, only used by TCP.
+            timeout (float): Seconds to wait for each attempt.
+            retries (int): Number of times to try again after a failed attempt.
+
+        Raises:
+            ConnectError: If the connection could not be opened.
+            ProtocolError: If the peer rejected the connection.
+            Timeout: If the handshake did not finish in time.
         """
         ...
 
@@ -54,29 +282,213 @@ class Network:
         """
         Decorator to register a function to global events.
 
+        Registering several functions to an event runs all of them, in the order they were registered.
+        Functions registered to "*" run for every event emitted by peers, after the handlers of the event, and receive the name of the event and its data.
+        Events emitted by peers with the name of an event of the network itself, such as "connect", "disconnect" or "error", do not reach its handlers.
+
+        Events of the network itself:
+            "connect" (peer): A peer connected.
+            "disconnect" (peer): A peer disconnected, with the reason in its disconnect_reason.
+            "rejected" (peer, reason): A peer failed the handshake.
+            "error" (error): A NetworkError from the background, such as a malformed message, a failed dial to a member, or an event sent with send_to() that could not be delivered. Without a handler it goes to the excepthook.
+            "discovered" (address): A network was found by discovery, see serve().
+            "reconnecting" (peer, attempt): A peer dialed with connect() is dialed again, attempts counting from 1.
+            "reconnected" (peer): A peer dialed again is back.
+
         Parameters:
             event (str): Name of the event to register to.
         """
         ...
 
-    def emit(self, event: str, data: str):
+    def once(self, event: str) -> Once:
+        """
+        Decorator to register a function that runs the next time the event is emitted only.
+
+        Parameters:
+            event (str): Name of the event to register to.
+        """
+        ...
+
+    def off(self, event: str, handler: Callable[..., object]):
+        """
+        Remove a function registered to an event with on() or once().
+
+        Parameters:
+            event (str): Name of the event the function is registered to.
+            handler (Callable): Function to remove.
+
+        Raises:
+            ValueError: If the function is not registered to the event.
+        """
+        ...
+
+    def emit(self, event: str, data: Data, flood: bool = False, ttl: int = 8):
         """
         Emit an event to all peers.
 
+        str data is received as str, binary data is received as bytes. Any other data is encoded with the codec negotiated with each peer and received as the decoded Python object.
+        Peers using the old 0x04 delimited format do not receive binary data, and receive other objects as JSON strings.
+
+        With flood enabled the event also reaches networks that are not connected to this one directly. Every network relays it to its own peers the first time it arrives, until it has made ttl hops, and runs its handlers only once however many paths the event takes.
+        Handlers registered on a peer run for flooded events only where the emitting network is that peer. Peers of older versions receive the event directly but do not relay it.
+
+        Parameters:
+            event (str): Name of the event to emit.
+            data (str | bytes | bytearray | memoryview | dict | list | int | float | bool | None): Data to send to all peers.
+            flood (bool): Whether to relay the event through the whole mesh.
+            ttl (int): Maximum number of hops a flooded event makes, at least 1.
+        """
+        ...
+
+    def emit_unreliable(self, event: str, data: Data):
+        """
+        Emit an event to all peers over UDP.
+
+        See Peer.emit_unreliable.
+
         Parameters:
             event (str): Name of the event to emit.
-            data (str): Data to send to all peers.
+            data (str | bytes | bytearray | memoryview | dict | list | int | float | bool | None): Data to send to all peers.
         """
         ...
 
-    def serve(self, tcp=True, udp=True):
+    def join(self, room: str):
+        """
+        Join a room, so events emitted to it reach this network. Peers are told right away, and peers that connect later when they connect.
+
+        Parameters:
+            room (str): Name of the room, between 1 and 255 bytes long.
+        """
+        ...
+
+    def leave(self, room: str):
+        """
+        Leave a room, so events emitted to it no longer reach this network.
+
+        Parameters:
+            room (str): Name of the room.
+        """
+        ...
+
+    def room(self, name: str) -> Room:
+        """
+        Get a room of the network, to emit events to it or register handlers on it, whether or not the network joined it.
+
+        Parameters:
+            name (str): Name of the room, between 1 and 255 bytes long.
+        """
+        ...
+
+    def subscribe(self, pattern: str) -> Event:
+        """
+        Decorator to register a function to the topics matching a pattern. The function should take two parameters, the topic and the data.
+
+        Topics are words separated by dots, such as "board.1.draw". In patterns "*" matches exactly one word and "#" any number of words, including none, so "chat.*" matches "chat.lobby" and "board.1.#" matches "board.1" and "board.1.draw".
+        Peers are told about the pattern right away, and peers that connect later when they connect, so they only publish matching topics to this network. Subscribing to the same pattern again adds the function to the ones it has, which run in the order they were subscribed.
+        Patterns are at most 255 bytes long, and a network subscribes to at most 256 of them.
+
+        Parameters:
+            pattern (str): Topic pattern to subscribe to.
+
+        Raises:
+            ValueError: If the pattern is invalid or there are too many subscriptions.
+        """
+        ...
+
+    def unsubscribe(self, pattern: str, handler: Callable[..., object] | None = None):
+        """
+        Stop receiving the topics matching a pattern passed to subscribe().
+
+        With a handler only that function is removed, and the pattern stays subscribed until its last function is removed.
+
+        Parameters:
+            pattern (str): Topic pattern to unsubscribe from.
+            handler (Callable | None): Function to remove, or None to remove them all.
+
+        Raises:
+            ValueError: If the handler is not subscribed to the pattern.
+        """
+        ...
+
+    def publish(self, topic: str, data: Data):
+        """
+        Publish data on a topic to the peers subscribed to a pattern matching it. Every matching subscription of a peer receives it once.
+
+        Parameters:
+            topic (str): Topic to publish on, without wildcards.
+            data (str | bytes | bytearray | memoryview | dict | list | int | float | bool | None): Data to send to the peers.
+        """
+        ...
+
+    def send_to(self, node_id: str, event: str, data: Data):
+        """
+        Emit an event to a single network, which need not be connected to this one directly.
+
+        The event is relayed by the peers along the shortest route to the network, learned from the member lists. Handlers registered on the peer that is the sending network run when it is connected directly, otherwise the network's handlers.
+        When there is no route to the network, or the route is longer than 16 hops, a ConnectError is passed to the "error" handler of this network. Routes are only relayed by peers of this version, so peers of older versions only receive events sent to them directly.
+        serve() must be called first.
+
+        Parameters:
+            node_id (str): Node id of the network to send the event to.
+            event (str): Name of the event to emit.
+            data (str | bytes | bytearray | memoryview | dict | list | int | float | bool | None): Data to send to the network.
+        """
+        ...
+
+    def serve(self, tcp=True, udp=True, websocket=False, discovery=False, group="239.255.77.77", advertise=False):
         """
         Serve as a peer-to-peer network.
 
         Both TCP and UDP is required to support a functioning peer-to-peer network. But for testing purposes, it is possible to serve only one of them.
+        With the "unix" and "memory" transports, tcp serves connections over that transport and udp is ignored.
+
+        With websocket enabled, connections that open with a WebSocket upgrade request are accepted as peers on the same port, over TLS when the network uses it, so browsers can join with new WebSocket("ws://host:port") or "wss://host:port".
+        Browsers send and receive text messages holding JSON objects of the form {"event": ..., "data": ...}. Events with binary data are sent to them as binary messages in the frame format used between networks.
+
+        With discovery enabled, the network announces its TCP port every few seconds to the multicast group or broadcast address on UDP port 47777, and connects to the networks it hears with the same app.
+        Discovery needs tcp, and works whichever port each network serves on, including several networks on one host. Each discovered network is reported once with a "discovered" event receiving its address.
+
+        Every socket is bound before serve() returns. If any cannot be bound, nothing is served and BindError is raised, so serve() can be called again.
+
+        With advertise enabled, the network is advertised over mDNS as a "_tknetwork._tcp.local" service, with TXT records holding its app and the tknetwork version, so other networks can find it with browse(). This needs tcp.
 
         Parameters:
             tcp (bool): Whether to serve TCP connections required to connect.
             udp (bool): Whether to serve UDP connections required to allow connections.
+            websocket (bool): Whether to accept WebSocket clients such as browsers.
+            discovery (bool): Whether to find and connect to networks on the local network.
+            group (str): Multicast group or broadcast address to announce on, such as "255.255.255.255" or "ff02::1".
+            advertise (bool): Whether to advertise the network over mDNS.
+
+        Raises:
+            BindError: If a socket could not be bound.
+        """
+        ...
+
+    def close(self):
+        """
+        Close the network and disconnect from every peer.
+
+        The network stops accepting connections, disconnects every peer with the reason "closed", and waits for its threads to end, so the addresses it served on can be used again. Handlers still run for the peers that disconnect. Closing a network again does nothing, and a closed network cannot serve again.
+        Using the network as a context manager closes it when the block ends, and networks still open when the interpreter exits are closed then.
+        """
+        ...
+
+    def __enter__(self) -> Network: ...
+
+    def __exit__(self, exc_type, exc_value, traceback) -> bool: ...
+
+    def browse(self, timeout: float = 1.0) -> list[Service]:
+        """
+        List the networks advertised over mDNS on the local network, including this one.
+
+        Queries are sent on the interface of the IPv4 address the network was created with, or on the default interface.
+        Each service is a dict with its instance "name", its "address" to pass to connect(), and the "app" and "version" from its TXT records.
+
+        Parameters:
+            timeout (float): Seconds to wait for answers.
+
+        Raises:
+            ValueError: If the timeout is negative or not finite.
         """
         ...
@@ -0,0 +1,282 @@
+use pyo3::prelude::*;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use std::fmt;
+use std::io;
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener, ToSocketAddrs, UdpSocket,
+};
+
+use crate::codec::Value;
+
+const BACKLOG: i32 = 128;
+
+// Where a peer is reached. IP addresses are kept as socket addresses so
+// IPv6 literals never have to be split out of strings.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    Ip(SocketAddr),
+    Unix(String),
+    Memory(String),
+}
+
+impl Address {
+    pub fn ip(address: SocketAddr) -> Self {
+        Self::Ip(canonical(address))
+    }
+
+    pub const fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Ip(address) => Some(*address),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(address) => write!(f, "{address}"),
+            Self::Unix(path) => write!(f, "unix:{path}"),
+            Self::Memory(name) => write!(f, "memory:{name}"),
+        }
+    }
+}
+
+// IP addresses become the tuples used by Python's socket module.
+impl ToPyObject for Address {
+    fn to_object(&self, py: Python) -> PyObject {
+        match self {
+            Self::Ip(SocketAddr::V4(address)) => {
+                (address.ip().to_string(), address.port()).to_object(py)
+            }
+            Self::Ip(SocketAddr::V6(address)) => (
+                address.ip().to_string(),
+                address.port(),
+                address.flowinfo(),
+                address.scope_id(),
+            )
+                .to_object(py),
+            Self::Unix(path) => path.to_object(py),
+            Self::Memory(name) => name.to_object(py),
+        }
+    }
+}
+
+// IPv4 peers of a dual-stack socket show up as IPv4-mapped IPv6 addresses.
+pub fn canonical(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => address,
+        },
+        SocketAddr::V4(_) => address,
+    }
+}
+
+// A socket bound to IPv6 can only send to IPv6 addresses, even when it is
+// dual-stack.
+pub fn reachable_from(local: SocketAddr, target: SocketAddr) -> SocketAddr {
+    match (local, target) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+            SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0).into()
+        }
+        _ => target,
+    }
+}
+
+// Where a socket bound to every interface can reach itself.
+pub fn loopback(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, address.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, address.port()).into(),
+        _ => address,
+    }
+}
+
+pub fn resolve(host: &str, port: u16) -> io::Result<SocketAddr> {
+    (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("Could not resolve '{host}'"),
+        )
+    })
+}
+
+pub fn bind_tcp(host: &str, port: u16) -> io::Result<TcpListener> {
+    let socket = bind(resolve(host, port)?, Type::STREAM, Protocol::TCP)?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+pub fn bind_udp(host: &str, port: u16) -> io::Result<UdpSocket> {
+    Ok(bind(resolve(host, port)?, Type::DGRAM, Protocol::UDP)?.into())
+}
+
+// IPv6 sockets also accept IPv4, which most systems do by default but
+// Windows does not.
+fn bind(address: SocketAddr, kind: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(address), kind, Some(protocol))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    // Matches std, so a restarted network can bind its port again right away.
+    #[cfg(unix)]
+    if kind == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&address.into())?;
+    Ok(socket)
+}
+
+// Connection requests carry addresses as a map rather than an "ip:port"
+// string.
+pub fn to_value(address: SocketAddr) -> Value {
+    let mut fields = vec![
+        (
+            Value::Text("ip".to_string()),
+            Value::Text(address.ip().to_string()),
+        ),
+        (
+            Value::Text("port".to_string()),
+            Value::UInt(address.port().into()),
+        ),
+    ];
+    if let SocketAddr::V6(v6) = address {
+        fields.push((
+            Value::Text("scope_id".to_string()),
+            Value::UInt(v6.scope_id().into()),
+        ));
+    }
+    Value::Map(fields)
+}
+
+pub fn from_value(value: &Value) -> Option<SocketAddr> {
+    let Value::Map(fields) = value else {
+        return None;
+    };
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(key, _)| matches!(key, Value::Text(key) if key == name))
+            .map(|(_, value)| value)
+    };
+    let integer = |value: &Value| match value {
+        Value::Int(value) => u64::try_from(*value).ok(),
+        Value::UInt(value) => Some(*value),
+        _ => None,
+    };
+
+    let Some(Value::Text(ip)) = field("ip") else {
+        return None;
+    };
+    let ip: IpAddr = ip.parse().ok()?;
+    let port = u16::try_from(field("port").and_then(integer)?).ok()?;
+    Some(match ip {
+        IpAddr::V4(_) => SocketAddr::new(ip, port),
+        IpAddr::V6(ip) => {
+            let scope_id = field("scope_id").and_then(integer).unwrap_or(0);
+            SocketAddrV6::new(ip, port, 0, u32::try_from(scope_id).ok()?).into()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(text: &str) -> SocketAddr {
+        text.parse().unwrap()
+    }
+
+    fn map(fields: &[(&str, Value)]) -> Value {
+        Value::Map(
+            fields
+                .iter()
+                .map(|(key, value)| (Value::Text((*key).to_string()), value.clone()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn values_round_trip() {
+        for text in ["192.0.2.1:5000", "[2001:db8::1]:5000", "[::1]:0"] {
+            assert_eq!(from_value(&to_value(address(text))), Some(address(text)));
+        }
+        let scoped = SocketAddrV6::new("fe80::1".parse().unwrap(), 5000, 0, 3).into();
+        assert_eq!(from_value(&to_value(scoped)), Some(scoped));
+    }
+
+    #[test]
+    fn values_from_other_codecs() {
+        let ip = || ("ip", Value::Text("fe80::1".to_string()));
+        let value = map(&[ip(), ("port", Value::Int(80)), ("scope_id", Value::Int(2))]);
+        let expected = SocketAddrV6::new("fe80::1".parse().unwrap(), 80, 0, 2).into();
+        assert_eq!(from_value(&value), Some(expected));
+        // Older peers leave out the scope id.
+        let value = map(&[ip(), ("port", Value::UInt(80))]);
+        assert_eq!(from_value(&value), Some(address("[fe80::1]:80")));
+    }
+
+    #[test]
+    fn malformed_values() {
+        let ip = || ("ip", Value::Text("192.0.2.1".to_string()));
+        for value in [
+            Value::Text("192.0.2.1:80".to_string()),
+            map(&[("port", Value::UInt(80))]),
+            map(&[ip()]),
+            map(&[
+                ("ip", Value::Text("host".to_string())),
+                ("port", Value::UInt(80)),
+            ]),
+            map(&[("ip", Value::UInt(1)), ("port", Value::UInt(80))]),
+            map(&[ip(), ("port", Value::UInt(65536))]),
+            map(&[ip(), ("port", Value::Int(-1))]),
+            map(&[ip(), ("port", Value::Text("80".to_string()))]),
+            map(&[ip(), ("port", Value::Float(80.0))]),
+            map(&[
+                ("ip", Value::Text("::1".to_string())),
+                ("port", Value::UInt(80)),
+                ("scope_id", Value::UInt(u64::from(u32::MAX) + 1)),
+            ]),
+        ] {
+            assert_eq!(from_value(&value), None, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn mapped_addresses() {
+        assert_eq!(
+            canonical(address("[::ffff:192.0.2.1]:80")),
+            address("192.0.2.1:80")
+        );
+        assert_eq!(
+            canonical(address("[2001:db8::1]:80")),
+            address("[2001:db8::1]:80")
+        );
+        assert_eq!(canonical(address("192.0.2.1:80")), address("192.0.2.1:80"));
+        assert_eq!(
+            Address::ip(address("[::ffff:127.0.0.1]:80")).to_string(),
+            "127.0.0.1:80"
+        );
+
+        let (v4, v6) = (address("0.0.0.0:1"), address("[::]:1"));
+        assert_eq!(
+            reachable_from(v6, address("192.0.2.1:80")),
+            address("[::ffff:192.0.2.1]:80")
+        );
+        assert_eq!(
+            reachable_from(v4, address("192.0.2.1:80")),
+            address("192.0.2.1:80")
+        );
+        assert_eq!(reachable_from(v6, address("[::1]:80")), address("[::1]:80"));
+    }
+
+    #[test]
+    fn loopback_addresses() {
+        assert_eq!(loopback(address("0.0.0.0:80")), address("127.0.0.1:80"));
+        assert_eq!(loopback(address("[::]:80")), address("[::1]:80"));
+        assert_eq!(loopback(address("192.0.2.1:80")), address("192.0.2.1:80"));
+        assert_eq!(Address::Ip(address("[::1]:80")).to_string(), "[::1]:80");
+    }
+}
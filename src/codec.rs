@@ -0,0 +1,282 @@
+use pyo3::buffer::PyBuffer;
+use pyo3::exceptions::{PyOverflowError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{
+    PyBool, PyByteArray, PyBytes, PyDict, PyFloat, PyList, PyLong, PyString, PyTuple,
+};
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+
+use std::fmt;
+use std::io;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Codec {
+    pub const ALL: [Self; 3] = [Self::Json, Self::MessagePack, Self::Cbor];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::MessagePack => "msgpack",
+            Self::Cbor => "cbor",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|codec| codec.name() == name)
+    }
+
+    // Every codec is always available, so the preferred one is listed first
+    // followed by the rest.
+    pub fn supported(preferred: Self) -> Vec<&'static str> {
+        std::iter::once(preferred)
+            .chain(Self::ALL.into_iter().filter(|&codec| codec != preferred))
+            .map(Self::name)
+            .collect()
+    }
+
+    // Our own codec is used whenever the peer can decode it, otherwise the
+    // first codec from the peer's list that we know.
+    pub fn negotiate(preferred: Self, remote: &[String]) -> Option<Self> {
+        if remote.iter().any(|name| name == preferred.name()) {
+            return Some(preferred);
+        }
+        remote.iter().find_map(|name| Self::from_name(name))
+    }
+
+    pub fn encode(self, value: &Value) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Json => Ok(serde_json::to_vec(value)?),
+            Self::MessagePack => rmp_serde::to_vec(value).map_err(invalid_data),
+            Self::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::ser::into_writer(value, &mut buffer).map_err(invalid_data)?;
+                Ok(buffer)
+            }
+        }
+    }
+
+    pub fn decode(self, buffer: &[u8]) -> io::Result<Value> {
+        match self {
+            Self::Json => Ok(serde_json::from_slice(buffer)?),
+            Self::MessagePack => rmp_serde::from_slice(buffer).map_err(invalid_data),
+            Self::Cbor => ciborium::de::from_reader(buffer).map_err(invalid_data),
+        }
+    }
+}
+
+impl<'source> FromPyObject<'source> for Codec {
+    fn extract(obj: &'source PyAny) -> PyResult<Self> {
+        let name: &str = obj.extract()?;
+        Self::from_name(name).ok_or_else(|| {
+            PyValueError::new_err(format!(
+                "Unknown codec '{name}', expected one of {}",
+                Self::ALL.map(Self::name).join(", ")
+            ))
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Text(String),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+impl Value {
+    pub fn to_py(&self, py: Python) -> PyResult<PyObject> {
+        Ok(match self {
+            Self::None => py.None(),
+            Self::Bool(value) => value.to_object(py),
+            Self::Int(value) => value.to_object(py),
+            Self::UInt(value) => value.to_object(py),
+            Self::Float(value) => value.to_object(py),
+            Self::Text(value) => value.to_object(py),
+            Self::Bytes(value) => PyBytes::new(py, value).into(),
+            Self::List(values) => {
+                let items = values
+                    .iter()
+                    .map(|value| value.to_py(py))
+                    .collect::<PyResult<Vec<_>>>()?;
+                PyList::new(py, items).into()
+            }
+            Self::Map(entries) => {
+                let dict = PyDict::new(py);
+                for (key, value) in entries {
+                    dict.set_item(key.to_py(py)?, value.to_py(py)?)?;
+                }
+                dict.into()
+            }
+        })
+    }
+}
+
+impl<'source> FromPyObject<'source> for Value {
+    fn extract(obj: &'source PyAny) -> PyResult<Self> {
+        if obj.is_none() {
+            Ok(Self::None)
+        } else if let Ok(value) = obj.downcast::<PyBool>() {
+            Ok(Self::Bool(value.is_true()))
+        } else if obj.downcast::<PyLong>().is_ok() {
+            obj.extract()
+                .map(Self::Int)
+                .or_else(|_| obj.extract().map(Self::UInt))
+                .map_err(|_| PyOverflowError::new_err("int too large to encode"))
+        } else if let Ok(value) = obj.downcast::<PyFloat>() {
+            Ok(Self::Float(value.value()))
+        } else if let Ok(value) = obj.downcast::<PyString>() {
+            Ok(Self::Text(value.to_str()?.to_string()))
+        } else if let Ok(value) = obj.downcast::<PyBytes>() {
+            Ok(Self::Bytes(value.as_bytes().to_vec()))
+        } else if let Ok(value) = obj.downcast::<PyByteArray>() {
+            Ok(Self::Bytes(value.to_vec()))
+        } else if let Ok(value) = obj.downcast::<PyList>() {
+            value
+                .iter()
+                .map(Self::extract)
+                .collect::<PyResult<_>>()
+                .map(Self::List)
+        } else if let Ok(value) = obj.downcast::<PyTuple>() {
+            value
+                .iter()
+                .map(Self::extract)
+                .collect::<PyResult<_>>()
+                .map(Self::List)
+        } else if let Ok(value) = obj.downcast::<PyDict>() {
+            value
+                .iter()
+                .map(|(key, value)| Ok((Self::extract(key)?, Self::extract(value)?)))
+                .collect::<PyResult<_>>()
+                .map(Self::Map)
+        } else if let Ok(buffer) = PyBuffer::<u8>::get(obj) {
+            buffer.to_vec(obj.py()).map(Self::Bytes)
+        } else {
+            Err(PyTypeError::new_err(format!(
+                "Cannot encode object of type '{}'",
+                obj.get_type().name()?
+            )))
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::None => serializer.serialize_none(),
+            Self::Bool(value) => serializer.serialize_bool(*value),
+            Self::Int(value) => serializer.serialize_i64(*value),
+            Self::UInt(value) => serializer.serialize_u64(*value),
+            Self::Float(value) => serializer.serialize_f64(*value),
+            Self::Text(value) => serializer.serialize_str(value),
+            Self::Bytes(value) => serializer.serialize_bytes(value),
+            Self::List(values) => {
+                let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            Self::Map(entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any value")
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<Value, E> {
+        Ok(Value::Bool(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Value, E> {
+        Ok(Value::Int(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Value, E> {
+        Ok(i64::try_from(value).map_or(Value::UInt(value), Value::Int))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Value, E> {
+        Ok(Value::Float(value))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Value, E> {
+        Ok(Value::Text(value.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<Value, E> {
+        Ok(Value::Text(value))
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Value, E> {
+        Ok(Value::Bytes(value.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<Value, E> {
+        Ok(Value::Bytes(value))
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Value::List(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Value::Map(entries))
+    }
+}
+
+fn invalid_data<E: fmt::Display>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::frame::{FrameType, MAGIC, VERSION};
+
+// Every datagram starts with a fixed header:
+//   magic (1) | version (1) | frame type (1) | token (8) | message id (4)
+//   | fragment index (2) | fragment count (2)
+pub const HEADER_LEN: usize = 19;
+// Keeps datagrams below the usual path MTU so they are not fragmented by IP.
+pub const MAX_DATAGRAM_LEN: usize = 1200;
+pub const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+const FRAGMENT_LEN: usize = MAX_DATAGRAM_LEN - HEADER_LEN;
+// The most fragments a message of MAX_MESSAGE_LEN is split into, more are
+// never waited for.
+const MAX_FRAGMENTS: usize = MAX_MESSAGE_LEN.div_ceil(FRAGMENT_LEN);
+const STALE_AFTER: Duration = Duration::from_secs(2);
+const MAX_PENDING: usize = 64;
+
+pub struct Datagram<'a> {
+    pub token: u64,
+    pub id: u32,
+    pub index: u16,
+    pub count: u16,
+    pub body: &'a [u8],
+}
+
+impl<'a> Datagram<'a> {
+    pub fn parse(buffer: &'a [u8]) -> Option<Self> {
+        let (header, body) = buffer.split_at_checked(HEADER_LEN)?;
+        if header[0] != MAGIC || header[1] != VERSION || header[2] != FrameType::Datagram as u8 {
+            return None;
+        }
+
+        let datagram = Self {
+            token: u64::from_be_bytes(header[3..11].try_into().ok()?),
+            id: u32::from_be_bytes(header[11..15].try_into().ok()?),
+            index: u16::from_be_bytes(header[15..17].try_into().ok()?),
+            count: u16::from_be_bytes(header[17..19].try_into().ok()?),
+            body,
+        };
+        let valid = datagram.index < datagram.count && datagram.count as usize <= MAX_FRAGMENTS;
+        valid.then_some(datagram)
+    }
+
+    pub fn split(token: u64, id: u32, message: &[u8]) -> Option<Vec<Vec<u8>>> {
+        if message.len() > MAX_MESSAGE_LEN {
+            return None;
+        }
+        let chunks: Vec<&[u8]> = if message.is_empty() {
+            vec![message]
+        } else {
+            message.chunks(FRAGMENT_LEN).collect()
+        };
+        let count = u16::try_from(chunks.len()).ok()?;
+
+        Some(
+            chunks
+                .into_iter()
+                .zip(0..)
+                .map(|(chunk, index): (&[u8], u16)| {
+                    let mut buffer = Vec::with_capacity(HEADER_LEN + chunk.len());
+                    buffer.extend_from_slice(&[MAGIC, VERSION, FrameType::Datagram as u8]);
+                    buffer.extend_from_slice(&token.to_be_bytes());
+                    buffer.extend_from_slice(&id.to_be_bytes());
+                    buffer.extend_from_slice(&index.to_be_bytes());
+                    buffer.extend_from_slice(&count.to_be_bytes());
+                    buffer.extend_from_slice(chunk);
+                    buffer
+                })
+                .collect(),
+        )
+    }
+}
+
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    length: usize,
+    started: Instant,
+}
+
+// Collects fragments until a message is complete. Messages that are not
+// completed within `STALE_AFTER` are dropped, as are the oldest ones when
+// too many are in flight.
+#[derive(Default)]
+pub struct Reassembler {
+    pending: HashMap<(u64, u32), Partial>,
+}
+
+impl Reassembler {
+    pub fn insert(&mut self, datagram: &Datagram) -> Option<Vec<u8>> {
+        if datagram.count == 1 {
+            return Some(datagram.body.to_vec());
+        }
+
+        let now = Instant::now();
+        self.pending
+            .retain(|_, partial| now.duration_since(partial.started) < STALE_AFTER);
+
+        let key = (datagram.token, datagram.id);
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, partial)| partial.started)
+                .map(|(&key, _)| key);
+            if let Some(oldest) = oldest {
+                self.pending.remove(&oldest);
+            }
+        }
+
+        let partial = self.pending.entry(key).or_insert_with(|| Partial {
+            fragments: vec![None; datagram.count as usize],
+            received: 0,
+            length: 0,
+            started: now,
+        });
+        if partial.fragments.len() != datagram.count as usize {
+            return None;
+        }
+
+        let fragment = &mut partial.fragments[datagram.index as usize];
+        if fragment.is_none() {
+            partial.length += datagram.body.len();
+            if partial.length > MAX_MESSAGE_LEN {
+                self.pending.remove(&key);
+                return None;
+            }
+            *fragment = Some(datagram.body.to_vec());
+            partial.received += 1;
+        }
+
+        if partial.received < partial.fragments.len() {
+            return None;
+        }
+        self.pending
+            .remove(&key)
+            .map(|partial| partial.fragments.into_iter().flatten().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reassemble(reassembler: &mut Reassembler, datagrams: &[Vec<u8>]) -> Option<Vec<u8>> {
+        datagrams
+            .iter()
+            .filter_map(|buffer| reassembler.insert(&Datagram::parse(buffer).unwrap()))
+            .last()
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        let mut reassembler = Reassembler::default();
+        for length in [0, 1, FRAGMENT_LEN, FRAGMENT_LEN + 1, MAX_MESSAGE_LEN] {
+            let message: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            let datagrams = Datagram::split(7, 1, &message).unwrap();
+            assert_eq!(datagrams.len(), length.div_ceil(FRAGMENT_LEN).max(1));
+            assert!(datagrams.iter().all(|d| d.len() <= MAX_DATAGRAM_LEN));
+            assert_eq!(reassemble(&mut reassembler, &datagrams), Some(message));
+        }
+        assert!(Datagram::split(7, 1, &vec![0; MAX_MESSAGE_LEN + 1]).is_none());
+    }
+
+    #[test]
+    fn fragments_in_any_order() {
+        let message = vec![3; FRAGMENT_LEN * 3];
+        let mut datagrams = Datagram::split(7, 1, &message).unwrap();
+        datagrams.reverse();
+        datagrams.insert(1, datagrams[0].clone());
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassemble(&mut reassembler, &datagrams), Some(message));
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn messages_are_kept_apart() {
+        let first = Datagram::split(7, 1, &vec![1; FRAGMENT_LEN * 2]).unwrap();
+        let second = Datagram::split(8, 1, &vec![2; FRAGMENT_LEN * 2]).unwrap();
+        let mut reassembler = Reassembler::default();
+        let mut insert = |buffer: &Vec<u8>| reassembler.insert(&Datagram::parse(buffer).unwrap());
+        assert_eq!(insert(&first[0]), None);
+        assert_eq!(insert(&second[1]), None);
+        assert_eq!(insert(&second[0]), Some(vec![2; FRAGMENT_LEN * 2]));
+        assert_eq!(insert(&first[1]), Some(vec![1; FRAGMENT_LEN * 2]));
+    }
+
+    #[test]
+    fn malformed_datagrams() {
+        let datagram = Datagram::split(7, 1, b"body").unwrap().remove(0);
+        let parsed = Datagram::parse(&datagram).unwrap();
+        assert_eq!((parsed.token, parsed.id, parsed.body), (7, 1, &b"body"[..]));
+
+        assert!(Datagram::parse(&datagram[..HEADER_LEN - 1]).is_none());
+        let mut wrong_magic = datagram.clone();
+        wrong_magic[0] ^= 0xff;
+        assert!(Datagram::parse(&wrong_magic).is_none());
+
+        let with_fragments = |index: u16, count: u16| {
+            let mut buffer = datagram.clone();
+            buffer[15..17].copy_from_slice(&index.to_be_bytes());
+            buffer[17..19].copy_from_slice(&count.to_be_bytes());
+            buffer
+        };
+        assert!(Datagram::parse(&with_fragments(1, 1)).is_none());
+        assert!(Datagram::parse(&with_fragments(0, 0)).is_none());
+        assert!(Datagram::parse(&with_fragments(0, MAX_FRAGMENTS as u16)).is_some());
+        assert!(Datagram::parse(&with_fragments(0, MAX_FRAGMENTS as u16 + 1)).is_none());
+        assert!(Datagram::parse(&with_fragments(0, u16::MAX)).is_none());
+    }
+
+    #[test]
+    fn oversized_messages_are_dropped() {
+        let mut datagram = Datagram::split(7, 1, &vec![0; FRAGMENT_LEN])
+            .unwrap()
+            .remove(0);
+        datagram.extend_from_slice(&vec![0; MAX_MESSAGE_LEN]);
+        datagram[17..19].copy_from_slice(&2u16.to_be_bytes());
+        let mut reassembler = Reassembler::default();
+        assert_eq!(
+            reassembler.insert(&Datagram::parse(&datagram).unwrap()),
+            None
+        );
+        assert!(reassembler.pending.is_empty());
+    }
+}
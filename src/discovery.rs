@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::frame::{FrameType, MAGIC, VERSION};
+use crate::shutdown::Shutdown;
+
+pub const GROUP: &str = "239.255.77.77";
+// Every network announces to and listens on this port, whichever port it
+// serves on, so networks on one host share it.
+pub const PORT: u16 = 47777;
+pub const INTERVAL: Duration = Duration::from_secs(2);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_PACKET_LEN: usize = 1024;
+
+// Announcements are sent to the group on the discovery port:
+//   magic (1) | version (1) | frame type (1) | JSON announcement
+// The port in the announcement is the one the network serves TCP on.
+#[derive(Serialize, Deserialize)]
+pub struct Announcement {
+    pub app: String,
+    pub id: String,
+    pub port: u16,
+}
+
+pub struct Discovery {
+    pub id: String,
+    app: String,
+    port: u16,
+    socket: UdpSocket,
+    target: SocketAddr,
+}
+
+impl Discovery {
+    // Joins the group on the interface, or allows broadcasting to it when it
+    // is not a multicast address.
+    pub fn bind(
+        interface: Ipv4Addr,
+        group: &str,
+        app: &str,
+        id: &str,
+        port: u16,
+    ) -> io::Result<Self> {
+        let ip: IpAddr = group.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid discovery group '{group}'"),
+            )
+        })?;
+        let (domain, unspecified) = match ip {
+            IpAddr::V4(_) => (Domain::IPV4, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            IpAddr::V6(_) => (Domain::IPV6, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        };
+        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::new(unspecified, PORT).into())?;
+        match ip {
+            IpAddr::V4(ip) if ip.is_multicast() => {
+                socket.join_multicast_v4(&ip, &interface)?;
+                socket.set_multicast_if_v4(&interface)?;
+                socket.set_multicast_loop_v4(true)?;
+            }
+            IpAddr::V6(ip) if ip.is_multicast() => {
+                socket.join_multicast_v6(&ip, 0)?;
+                socket.set_multicast_loop_v6(true)?;
+            }
+            _ => socket.set_broadcast(true)?,
+        }
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        Ok(Self {
+            id: id.to_string(),
+            app: app.to_string(),
+            port,
+            socket: socket.into(),
+            target: SocketAddr::new(ip, PORT),
+        })
+    }
+
+    // Announces the network every `INTERVAL` and passes the address of every
+    // other network heard to found, once, with whether this one should dial
+    // it. Errors are passed to report, discovery keeps running.
+    pub fn run(
+        &self,
+        shutdown: &Shutdown,
+        report: impl Fn(io::Error),
+        found: impl Fn(SocketAddr, bool),
+    ) {
+        let mut announced: Option<Instant> = None;
+        let mut discovered = HashSet::new();
+        let mut buffer = vec![0; MAX_PACKET_LEN];
+        while !shutdown.is_closed() {
+            if announced.is_none_or(|announced| announced.elapsed() >= INTERVAL) {
+                if let Err(e) = self.announce() {
+                    report(e);
+                }
+                announced = Some(Instant::now());
+            }
+
+            let (bytes_read, address) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => {
+                    report(e);
+                    continue;
+                }
+            };
+            let Some(announcement) = self.parse(&buffer[..bytes_read]) else {
+                continue;
+            };
+            // Both networks hear each other, so only the one with the lower
+            // id dials to avoid connecting twice.
+            let dial = self.id < announcement.id;
+            if discovered.insert(announcement.id) {
+                found(SocketAddr::new(address.ip(), announcement.port), dial);
+            }
+        }
+    }
+
+    fn announce(&self) -> io::Result<()> {
+        let announcement = Announcement {
+            app: self.app.clone(),
+            id: self.id.clone(),
+            port: self.port,
+        };
+        let mut packet = vec![MAGIC, VERSION, FrameType::Announce as u8];
+        packet.extend_from_slice(&serde_json::to_vec(&announcement)?);
+        self.socket.send_to(&packet, self.target)?;
+        Ok(())
+    }
+
+    // Returns announcements sent by other nodes of the same application.
+    fn parse(&self, packet: &[u8]) -> Option<Announcement> {
+        let (header, body) = packet.split_at_checked(3)?;
+        if header != [MAGIC, VERSION, FrameType::Announce as u8] {
+            return None;
+        }
+        let announcement: Announcement = serde_json::from_slice(body).ok()?;
+        (announcement.app == self.app && announcement.id != self.id).then_some(announcement)
+    }
+}
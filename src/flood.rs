@@ -0,0 +1,128 @@
+use std::collections::{HashSet, VecDeque};
+
+pub const DEFAULT_TTL: u8 = 8;
+// Enough to recognise messages that take a longer path through the mesh,
+// without keeping every id ever seen.
+const SEEN_CAPACITY: usize = 4096;
+
+// A message flooded through the mesh is relayed by every peer that
+// receives it for the first time:
+//   message id (8) | hops left (1) | origin length (1) | origin node id
+//   | encoded message
+pub struct Flood<'a> {
+    pub id: u64,
+    pub ttl: u8,
+    pub origin: &'a str,
+    pub message: &'a [u8],
+}
+
+impl<'a> Flood<'a> {
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        let origin = u8::try_from(self.origin.len()).ok()?;
+        let mut buffer = Vec::with_capacity(10 + self.origin.len() + self.message.len());
+        buffer.extend_from_slice(&self.id.to_be_bytes());
+        buffer.push(self.ttl);
+        buffer.push(origin);
+        buffer.extend_from_slice(self.origin.as_bytes());
+        buffer.extend_from_slice(self.message);
+        Some(buffer)
+    }
+
+    pub fn parse(buffer: &'a [u8]) -> Option<Self> {
+        let (header, rest) = buffer.split_at_checked(10)?;
+        let (origin, message) = rest.split_at_checked(header[9] as usize)?;
+        Some(Self {
+            id: u64::from_be_bytes(header[..8].try_into().ok()?),
+            ttl: header[8],
+            origin: std::str::from_utf8(origin).ok()?,
+            message,
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct SeenCache {
+    ids: HashSet<u64>,
+    order: VecDeque<u64>,
+}
+
+impl SeenCache {
+    // Returns false when the id was already seen.
+    pub fn insert(&mut self, id: u64) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn floods_round_trip() {
+        let flood = Flood {
+            id: u64::MAX - 1,
+            ttl: DEFAULT_TTL,
+            origin: "node",
+            message: b"message",
+        };
+        let buffer = flood.encode().unwrap();
+        let parsed = Flood::parse(&buffer).unwrap();
+        assert_eq!((parsed.id, parsed.ttl), (u64::MAX - 1, DEFAULT_TTL));
+        assert_eq!((parsed.origin, parsed.message), ("node", &b"message"[..]));
+
+        let empty = Flood {
+            origin: "",
+            message: b"",
+            ..flood
+        };
+        let buffer = empty.encode().unwrap();
+        assert_eq!(Flood::parse(&buffer).unwrap().origin, "");
+    }
+
+    #[test]
+    fn malformed_floods() {
+        let origin = "a".repeat(u8::MAX as usize + 1);
+        let flood = Flood {
+            id: 1,
+            ttl: 1,
+            origin: &origin,
+            message: b"",
+        };
+        assert!(flood.encode().is_none());
+
+        let buffer = Flood {
+            origin: "node",
+            ..flood
+        }
+        .encode()
+        .unwrap();
+        assert!(Flood::parse(&buffer[..9]).is_none());
+        assert!(Flood::parse(&buffer[..12]).is_none());
+        let mut invalid_origin = buffer;
+        invalid_origin[10] = 0xff;
+        assert!(Flood::parse(&invalid_origin).is_none());
+    }
+
+    #[test]
+    fn seen_ids() {
+        let mut seen = SeenCache::default();
+        assert!(seen.insert(1));
+        assert!(!seen.insert(1));
+        for id in 2..=SEEN_CAPACITY as u64 {
+            assert!(seen.insert(id));
+        }
+        assert!(!seen.insert(1));
+        assert!(seen.insert(0));
+        assert!(seen.insert(1));
+        assert!(!seen.insert(SEEN_CAPACITY as u64));
+    }
+}
@@ -0,0 +1,324 @@
+use std::io::{self, BufRead, BufReader, Read};
+
+use crate::websocket::{self, MessageReader, Opcode};
+
+// Every frame starts with a fixed header:
+//   magic (1) | version (1) | frame type (1) | body length (4, big endian)
+pub const MAGIC: u8 = 0xE7;
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 7;
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+// Frames sent by older clients are JSON terminated by this byte.
+const LEGACY_DELIMITER: u8 = 0x4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameType {
+    Message = 0,
+    Handshake = 1,
+    Reject = 2,
+    Datagram = 3,
+    Ping = 4,
+    Pong = 5,
+    Announce = 6,
+    Accept = 7,
+    Members = 8,
+    Flood = 9,
+    Routed = 10,
+    Rooms = 11,
+    Room = 12,
+    Subscriptions = 13,
+    Publish = 14,
+    Goodbye = 15,
+}
+
+impl TryFrom<u8> for FrameType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Message),
+            1 => Ok(Self::Handshake),
+            2 => Ok(Self::Reject),
+            3 => Ok(Self::Datagram),
+            4 => Ok(Self::Ping),
+            5 => Ok(Self::Pong),
+            6 => Ok(Self::Announce),
+            7 => Ok(Self::Accept),
+            8 => Ok(Self::Members),
+            9 => Ok(Self::Flood),
+            10 => Ok(Self::Routed),
+            11 => Ok(Self::Rooms),
+            12 => Ok(Self::Room),
+            13 => Ok(Self::Subscriptions),
+            14 => Ok(Self::Publish),
+            15 => Ok(Self::Goodbye),
+            other => Err(other),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    Binary,
+    Legacy,
+    WebSocket,
+}
+
+pub struct Frame {
+    pub kind: FrameType,
+    pub framing: Framing,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    pub fn encode(kind: FrameType, body: &[u8]) -> io::Result<Vec<u8>> {
+        let length = u32::try_from(body.len())
+            .ok()
+            .filter(|&length| length as usize <= MAX_FRAME_LEN)
+            .ok_or_else(|| invalid_data("Frame exceeds maximum length"))?;
+
+        let mut buffer = Vec::with_capacity(HEADER_LEN + body.len());
+        buffer.extend_from_slice(&[MAGIC, VERSION, kind as u8]);
+        buffer.extend_from_slice(&length.to_be_bytes());
+        buffer.extend_from_slice(body);
+        Ok(buffer)
+    }
+
+    pub fn encode_legacy(body: &[u8]) -> io::Result<Vec<u8>> {
+        if body.contains(&LEGACY_DELIMITER) {
+            return Err(invalid_data("Legacy frame cannot contain the delimiter"));
+        }
+        let mut buffer = Vec::with_capacity(body.len() + 1);
+        buffer.extend_from_slice(body);
+        buffer.push(LEGACY_DELIMITER);
+        Ok(buffer)
+    }
+}
+
+pub struct FrameReader<R> {
+    reader: BufReader<R>,
+    accept_websocket: bool,
+    websocket: Option<MessageReader>,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            accept_websocket: false,
+            websocket: None,
+        }
+    }
+
+    // Lets the stream open with a WebSocket upgrade request, which is read
+    // as a handshake frame whose body is the client's key. Every frame after
+    // it is read from WebSocket messages.
+    pub const fn accept_websocket(mut self) -> Self {
+        self.accept_websocket = true;
+        self
+    }
+
+    pub fn read_frame(&mut self) -> io::Result<Frame> {
+        loop {
+            if self.websocket.is_some() {
+                return self.read_websocket();
+            }
+
+            let first = match self.reader.fill_buf()?.first() {
+                Some(&byte) => byte,
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+
+            let frame = match first {
+                MAGIC => self.read_binary()?,
+                b'{' => Some(self.read_legacy()?),
+                b'G' if self.accept_websocket => {
+                    let key = websocket::read_request(&mut self.reader)?;
+                    self.websocket = Some(MessageReader::default());
+                    Some(Frame {
+                        kind: FrameType::Handshake,
+                        framing: Framing::WebSocket,
+                        body: key.into_bytes(),
+                    })
+                }
+                _ => return Err(invalid_data("Unknown frame header")),
+            };
+
+            if let Some(frame) = frame {
+                return Ok(frame);
+            }
+        }
+    }
+
+    // Returns `None` for frame types this version does not know about,
+    // which are skipped so newer peers can extend the protocol.
+    fn read_binary(&mut self) -> io::Result<Option<Frame>> {
+        let mut header = [0; HEADER_LEN];
+        self.reader.read_exact(&mut header)?;
+
+        if header[1] != VERSION {
+            return Err(invalid_data("Unsupported frame version"));
+        }
+        let length = u32::from_be_bytes([header[3], header[4], header[5], header[6]]) as usize;
+        if length > MAX_FRAME_LEN {
+            return Err(invalid_data("Frame exceeds maximum length"));
+        }
+
+        let mut body = vec![0; length];
+        self.reader.read_exact(&mut body)?;
+
+        Ok(FrameType::try_from(header[2]).ok().map(|kind| Frame {
+            kind,
+            framing: Framing::Binary,
+            body,
+        }))
+    }
+
+    fn read_legacy(&mut self) -> io::Result<Frame> {
+        let mut body = Vec::new();
+        let limit = MAX_FRAME_LEN as u64 + 1;
+        (&mut self.reader)
+            .take(limit)
+            .read_until(LEGACY_DELIMITER, &mut body)?;
+
+        if body.last() == Some(&LEGACY_DELIMITER) {
+            body.pop();
+            Ok(Frame {
+                kind: FrameType::Message,
+                framing: Framing::Legacy,
+                body,
+            })
+        } else if body.len() as u64 == limit {
+            Err(invalid_data("Frame exceeds maximum length"))
+        } else {
+            Err(io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    // Text messages are JSON, binary messages carry the same body as a
+    // binary message frame.
+    fn read_websocket(&mut self) -> io::Result<Frame> {
+        let Some(websocket) = &mut self.websocket else {
+            return Err(invalid_data("Not a WebSocket stream"));
+        };
+        let (opcode, body) = websocket.read(&mut self.reader)?;
+
+        let (kind, framing) = match opcode {
+            Opcode::Text => (FrameType::Message, Framing::WebSocket),
+            Opcode::Binary => (FrameType::Message, Framing::Binary),
+            Opcode::Ping => (FrameType::Ping, Framing::WebSocket),
+            Opcode::Pong => (FrameType::Pong, Framing::WebSocket),
+            Opcode::Close | Opcode::Continuation => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+        Ok(Frame {
+            kind,
+            framing,
+            body,
+        })
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(bytes: &[u8]) -> io::Result<Frame> {
+        FrameReader::new(bytes).read_frame()
+    }
+
+    fn error_kind(bytes: &[u8]) -> io::ErrorKind {
+        read(bytes).err().unwrap().kind()
+    }
+
+    #[test]
+    fn binary_frames() {
+        let mut bytes = Frame::encode(FrameType::Message, b"first").unwrap();
+        bytes.extend(Frame::encode(FrameType::Ping, b"").unwrap());
+        let mut reader = FrameReader::new(&bytes[..]);
+
+        let frame = reader.read_frame().unwrap();
+        assert_eq!(frame.kind, FrameType::Message);
+        assert_eq!(frame.framing, Framing::Binary);
+        assert_eq!(frame.body, b"first");
+        let frame = reader.read_frame().unwrap();
+        assert_eq!((frame.kind, frame.body), (FrameType::Ping, Vec::new()));
+        assert_eq!(
+            reader.read_frame().err().unwrap().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn legacy_frames() {
+        let mut bytes = Frame::encode_legacy(br#"{"event": "a"}"#).unwrap();
+        bytes.extend(Frame::encode(FrameType::Message, b"binary").unwrap());
+        let mut reader = FrameReader::new(&bytes[..]);
+
+        let frame = reader.read_frame().unwrap();
+        assert_eq!(frame.framing, Framing::Legacy);
+        assert_eq!(frame.body, br#"{"event": "a"}"#);
+        assert_eq!(reader.read_frame().unwrap().body, b"binary");
+
+        assert!(Frame::encode_legacy(&[b'{', LEGACY_DELIMITER]).is_err());
+        assert_eq!(error_kind(br#"{"event""#), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn unknown_frame_types_are_skipped() {
+        let mut bytes = Frame::encode(FrameType::Message, b"skipped").unwrap();
+        bytes[2] = 0xff;
+        bytes.extend(Frame::encode(FrameType::Pong, b"kept").unwrap());
+
+        let frame = read(&bytes).unwrap();
+        assert_eq!(
+            (frame.kind, frame.body),
+            (FrameType::Pong, b"kept".to_vec())
+        );
+    }
+
+    #[test]
+    fn malformed_frames() {
+        let frame = Frame::encode(FrameType::Message, b"body").unwrap();
+        assert_eq!(error_kind(b"x"), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error_kind(&frame[..HEADER_LEN - 1]),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            error_kind(&frame[..frame.len() - 1]),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let mut wrong_version = frame.clone();
+        wrong_version[1] = VERSION + 1;
+        assert_eq!(error_kind(&wrong_version), io::ErrorKind::InvalidData);
+
+        let mut too_long = frame;
+        too_long[3..7].copy_from_slice(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes());
+        assert_eq!(error_kind(&too_long), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn websocket_streams() {
+        let request = "GET / HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+                       Sec-WebSocket-Key: key\r\n\r\n";
+        assert_eq!(error_kind(request.as_bytes()), io::ErrorKind::InvalidData);
+
+        let mut bytes = request.as_bytes().to_vec();
+        // A masked text frame holding "{}", with a zero mask.
+        bytes.extend_from_slice(&[0x81, 0x82, 0, 0, 0, 0, b'{', b'}']);
+        let mut reader = FrameReader::new(&bytes[..]).accept_websocket();
+        let frame = reader.read_frame().unwrap();
+        assert_eq!(frame.kind, FrameType::Handshake);
+        assert_eq!(frame.body, b"key");
+        let frame = reader.read_frame().unwrap();
+        assert_eq!(frame.kind, FrameType::Message);
+        assert_eq!(frame.framing, Framing::WebSocket);
+        assert_eq!(frame.body, b"{}");
+    }
+}
@@ -0,0 +1,111 @@
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use std::time::Duration;
+
+use crate::codec::Codec;
+
+// Bumped whenever a change to the frames makes older peers unable to talk
+// to newer ones.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const LIBRARY_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const FEATURES: [&str; 7] = ["binary", "codecs", MEMBERS, FLOOD, ROUTING, ROOMS, TOPICS];
+pub const MEMBERS: &str = "members";
+pub const FLOOD: &str = "flood";
+pub const ROUTING: &str = "routing";
+pub const ROOMS: &str = "rooms";
+pub const TOPICS: &str = "topics";
+pub const DATAGRAMS: &str = "datagrams";
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Handshake {
+    pub protocol: u32,
+    pub version: String,
+    pub app: String,
+    pub features: Vec<String>,
+    pub codecs: Vec<String>,
+    #[serde(default)]
+    pub udp: Option<UdpChannel>,
+    // The TCP port the sender accepts connections on, so the other side can
+    // tell its peers where to connect to it as well.
+    #[serde(default)]
+    pub port: Option<u16>,
+    // Identifies the sending network across all of its connections.
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+// Where and how to send datagrams to the side that sent the handshake.
+// The token is put in every datagram so it can be matched to its peer.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct UdpChannel {
+    pub port: u16,
+    pub token: u64,
+}
+
+impl Handshake {
+    pub fn new(
+        app: &str,
+        id: &str,
+        codec: Codec,
+        port: Option<u16>,
+        udp: Option<UdpChannel>,
+    ) -> Self {
+        let mut features: Vec<String> = FEATURES.map(str::to_string).to_vec();
+        if udp.is_some() {
+            features.push(DATAGRAMS.to_string());
+        }
+
+        Self {
+            protocol: PROTOCOL_VERSION,
+            version: LIBRARY_VERSION.to_string(),
+            app: app.to_string(),
+            features,
+            codecs: Codec::supported(codec)
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            udp,
+            port,
+            id: Some(id.to_string()),
+        }
+    }
+
+    pub fn check(&self, app: &str) -> Result<(), String> {
+        if self.protocol != PROTOCOL_VERSION {
+            return Err(format!(
+                "Protocol version mismatch: expected {PROTOCOL_VERSION}, got {} (tknetwork {})",
+                self.protocol, self.version
+            ));
+        }
+        if self.app != app {
+            return Err(format!(
+                "Application mismatch: expected '{app}', got '{}'",
+                self.app
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Reject {
+    pub reason: String,
+    // Set when the connection duplicates one to the sender, which keeps the
+    // other connection. Holds the sender's node id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate: Option<String>,
+}
+
+pub fn random_node_id() -> String {
+    format!("{:016x}", random_u64())
+}
+
+pub fn random_u64() -> u64 {
+    let mut bytes = [0; 8];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system random number generator failed");
+    u64::from_be_bytes(bytes)
+}
@@ -1,322 +1,2912 @@
+use pyo3::create_exception;
+use pyo3::exceptions::{PyOSError, PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyTuple};
+use pyo3::types::{PyBytes, PyDict, PyTuple};
 include!(concat!(env!("OUT_DIR"), "/module.rs"));
 
-use serde::{Deserialize, Serialize};
+mod address;
+mod codec;
+mod datagram;
+mod discovery;
+mod flood;
+mod frame;
+mod handshake;
+mod mdns;
+mod members;
+mod message;
+mod rooms;
+mod routing;
+mod shutdown;
+mod tls;
+mod topics;
+mod transport;
+mod websocket;
+mod writer;
 
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream, UdpSocket};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
+use address::Address;
+use codec::Codec;
+use datagram::{Datagram, Reassembler};
+use discovery::Discovery;
+use flood::{Flood, SeenCache};
+use frame::{Frame, FrameReader, FrameType, Framing};
+use handshake::{Handshake, Reject, UdpChannel};
+use members::Members;
+use message::{Message, Payload};
+use rooms::RoomMessage;
+use routing::{Routed, Undeliverable};
+use shutdown::Shutdown;
+use tls::{Tls, TlsStream};
+use transport::{Listener, Stream, Transport};
+use websocket::Opcode;
+use writer::Writer;
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc::{channel, Receiver, SendError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+create_exception!(tknetwork, NetworkError, PyOSError);
+create_exception!(tknetwork, ConnectError, NetworkError);
+create_exception!(tknetwork, ProtocolError, NetworkError);
+create_exception!(tknetwork, PeerClosed, NetworkError);
+create_exception!(tknetwork, Timeout, NetworkError);
+create_exception!(tknetwork, BindError, NetworkError);
+
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const DUPLICATE: &str = "Duplicate connection";
+// Sent to and by peers of older versions, which do not exchange member
+// lists, to connect them to a peer that just joined.
+const CONNECTION_REQUEST: &str = "connection_request";
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// Handlers registered with "*" receive every event emitted by peers,
+// with its name before the data.
+const WILDCARD: &str = "*";
+
+struct Handler {
+    callback: Py<PyAny>,
+    once: bool,
+}
 
 #[pyclass]
 struct Event {
-    callback: Option<Py<PyAny>>,
+    handlers: Vec<Handler>,
 }
 
 #[pymethods]
 impl Event {
     #[new]
     const fn new() -> Self {
-        Self { callback: None }
+        Self {
+            handlers: Vec::new(),
+        }
     }
 
     fn __call__(&mut self, py: Python, func: Py<PyAny>) -> Py<PyAny> {
-        self.callback = Some(func.clone_ref(py));
+        self.handlers.push(Handler {
+            callback: func.clone_ref(py),
+            once: false,
+        });
         func
     }
+}
+
+impl Event {
+    // Handlers run in the order they were registered. The event is not
+    // borrowed while they run, so they can register and remove handlers,
+    // and one that raises does not keep the others from running.
+    fn call(slf: &Py<Self>, py: Python, args: &PyTuple, mut report: impl FnMut(PyErr)) {
+        let callbacks: Vec<Py<PyAny>> = {
+            let mut event = slf.borrow_mut(py);
+            let callbacks = event
+                .handlers
+                .iter()
+                .map(|handler| handler.callback.clone_ref(py))
+                .collect();
+            event.handlers.retain(|handler| !handler.once);
+            callbacks
+        };
+        for callback in callbacks {
+            if let Err(e) = callback.call1(py, args) {
+                report(e);
+            }
+        }
+    }
 
-    fn call(&self, py: Python, args: &PyTuple, kwargs: Option<&PyDict>) -> PyResult<()> {
-        if let Some(callback) = &self.callback {
-            callback.call(py, args, kwargs)?;
+    // Removes every registration of the handler. Comparing runs Python
+    // code, which may register and remove handlers itself, so the event is
+    // not borrowed meanwhile and handlers that moved are left alone.
+    fn remove(slf: &Py<Self>, py: Python, handler: &PyAny) -> PyResult<bool> {
+        let callbacks: Vec<Py<PyAny>> = slf
+            .borrow(py)
+            .handlers
+            .iter()
+            .map(|handler| handler.callback.clone_ref(py))
+            .collect();
+        let mut matches = Vec::new();
+        for (index, callback) in callbacks.iter().enumerate() {
+            if handler.eq(callback)? {
+                matches.push(index);
+            }
+        }
+
+        let mut event = slf.borrow_mut(py);
+        let mut removed = false;
+        for index in matches.into_iter().rev() {
+            if event
+                .handlers
+                .get(index)
+                .is_some_and(|known| known.callback.is(&callbacks[index]))
+            {
+                event.handlers.remove(index);
+                removed = true;
+            }
         }
+        Ok(removed)
+    }
+}
+
+// Returned by once(), registers the function it decorates to run the next
+// time the event is emitted only.
+#[pyclass]
+struct Once {
+    event: Py<Event>,
+}
+
+#[pymethods]
+impl Once {
+    fn __call__(&self, py: Python, func: Py<PyAny>) -> Py<PyAny> {
+        self.event.borrow_mut(py).handlers.push(Handler {
+            callback: func.clone_ref(py),
+            once: true,
+        });
+        func
+    }
+}
+
+// Registering to an event again adds to its handlers.
+fn event(py: Python, events: &mut HashMap<String, Py<Event>>, name: String) -> PyResult<Py<Event>> {
+    if let Some(event) = events.get(&name) {
+        return Ok(event.clone_ref(py));
+    }
+    let event = Py::new(py, Event::new())?;
+    events.insert(name, event.clone_ref(py));
+    Ok(event)
+}
+
+// Events are looked up before their handlers run, which leaves the handlers
+// free to register and remove handlers themselves.
+fn handler(
+    py: Python,
+    events: &RefCell<HashMap<String, Py<Event>>>,
+    name: &str,
+) -> Option<Py<Event>> {
+    events.borrow().get(name).map(|event| event.clone_ref(py))
+}
+
+fn remove_handler(
+    py: Python,
+    events: &RefCell<HashMap<String, Py<Event>>>,
+    name: &str,
+    handler: &PyAny,
+) -> PyResult<()> {
+    let event = self::handler(py, events, name);
+    let removed = match event {
+        Some(event) => Event::remove(&event, py, handler)?,
+        None => false,
+    };
+    if removed {
         Ok(())
+    } else {
+        Err(PyValueError::new_err(format!(
+            "Handler is not registered to '{name}'"
+        )))
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Message {
-    event: String,
-    data: String,
+#[derive(Clone)]
+struct Config {
+    app: String,
+    legacy: bool,
+    codec: Codec,
+    websocket: bool,
+    port: Option<u16>,
+    id: String,
+    // How long a write to a peer may block before the peer is disconnected.
+    write_timeout: Option<Duration>,
+}
+
+// Peers are pinged every interval, and considered gone when nothing
+// arrived from them for the timeout.
+#[derive(Clone, Copy)]
+struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+}
+
+// The established peers of a network by node id, shared with its peers so
+// they can tell when a new connection duplicates an existing one.
+type Nodes = Arc<Mutex<HashMap<String, Py<Peer>>>>;
+
+type Reader = FrameReader<Box<dyn Read + Send>>;
+
+// Events that only the network itself raises. Peers emitting an event with
+// one of these names do not reach its handlers.
+const RESERVED: [&str; 7] = [
+    "connect",
+    "reconnected",
+    "disconnect",
+    "reconnecting",
+    "rejected",
+    "discovered",
+    "error",
+];
+
+// What a message to the listen thread of the network is about. Events
+// emitted by peers are kept apart from the network's own messages, so a
+// peer cannot pass one off as the other.
+enum Kind {
+    User(Message),
+    Connect,
+    Reconnected,
+    Disconnect,
+    Reconnecting(u64),
+    Rejected(String),
+    // A peer that connected to us, which our other peers are asked to
+    // connect to.
+    Introduce(SocketAddr),
+    // Asked by a peer of an older version to connect to another one.
+    ConnectionRequest(SocketAddr),
+    Discovered(SocketAddr),
+    Members(Vec<u8>),
+    Flood(Vec<u8>),
+    Routed(Vec<u8>),
+    Room(Vec<u8>),
+    Publish(Vec<u8>),
+    // Failures in background threads are raised to the "error" handler.
+    Error(PyErr),
+    // Exceptions raised by the handlers of a peer go to the excepthook of
+    // the network.
+    Exception(PyErr),
+    // Network.close sends this once every peer is gone.
+    Close,
 }
 
+// Sending fails only once the network is closed, when nobody is left to
+// handle the message.
 struct ThreadMessage {
-    event: String,
+    kind: Kind,
     peer: Option<Py<Peer>>,
-    data: String,
+}
+
+impl ThreadMessage {
+    const fn new(kind: Kind, peer: Option<Py<Peer>>) -> Self {
+        Self { kind, peer }
+    }
+
+    const fn error(peer: Option<Py<Peer>>, error: PyErr) -> Self {
+        Self::new(Kind::Error(error), peer)
+    }
+
+    const fn exception(error: PyErr) -> Self {
+        Self::new(Kind::Exception(error), None)
+    }
+}
+
+enum Rejection {
+    Local(String),
+    Remote(String),
+}
+
+// How the connection to a peer came about.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Origin {
+    Accepted,
+    // Dialed because another peer introduced it or listed it as a member.
+    Introduced,
+    // Dialed by Network.connect.
+    Connected,
+    // Dialed back after an old client asked to join with a datagram.
+    Requested,
+}
+
+enum Attempt {
+    Retry(PyErr),
+    Fail(PyErr),
 }
 
 #[pyclass]
 struct Peer {
     #[pyo3(get)]
     name: String,
-    events: HashMap<String, Py<Event>>,
-    socket: RefCell<TcpStream>,
+    address: Address,
+    events: RefCell<HashMap<String, Py<Event>>>,
+    socket: RefCell<Box<dyn Stream>>,
+    writer: RefCell<Writer>,
+    tls: RefCell<Option<TlsStream>>,
+    udp: RefCell<Option<Arc<UdpSocket>>>,
+    token: u64,
+    datagram_id: Cell<u32>,
+    config: Config,
+    remote: RefCell<Option<Handshake>>,
+    connected: Cell<bool>,
+    closed: RefCell<Option<String>>,
+    // Why the connection ended after it was established, such as "timeout",
+    // "reset" or "closed".
+    disconnect_reason: RefCell<Option<String>>,
+    last_received: Cell<Instant>,
+    // Set while a new connection takes the place of one that dropped.
+    reconnecting: Cell<bool>,
+    // Set once this side closed the connection, so the peer is not
+    // reconnected.
+    goodbye: Cell<bool>,
+    reader: RefCell<Option<JoinHandle<()>>>,
+    framing: Cell<Framing>,
+    // Set when the peer only reads the old 0x04 delimited format, so
+    // reconnections skip the handshake as well.
+    legacy: Cell<bool>,
+    codec: Cell<Codec>,
+    origin: Origin,
+    nodes: Nodes,
+    // The node id of the peer when the connection was dropped because
+    // another one to the same node was kept.
+    duplicate: RefCell<Option<String>>,
+    // Rooms the peer joined, as it last announced them.
+    rooms: RefCell<HashSet<String>>,
+    // Topic patterns the peer subscribes to, as it last announced them.
+    subscriptions: RefCell<Vec<String>>,
     tx: Sender<ThreadMessage>,
 }
 
 #[pymethods]
 impl Peer {
-    fn on(&mut self, py: Python, name: String) -> PyResult<Py<Event>> {
-        let event = Py::new(py, Event { callback: None })?;
-        self.events.insert(name, event.clone_ref(py));
-        Ok(event)
+    fn on(&self, py: Python, name: String) -> PyResult<Py<Event>> {
+        event(py, &mut self.events.borrow_mut(), name)
+    }
+
+    fn once(&self, py: Python, name: String) -> PyResult<Once> {
+        Ok(Once {
+            event: event(py, &mut self.events.borrow_mut(), name)?,
+        })
+    }
+
+    fn off(&self, py: Python, name: &str, handler: &PyAny) -> PyResult<()> {
+        remove_handler(py, &self.events, name, handler)
+    }
+
+    fn emit(&self, event: String, data: Payload) -> PyResult<()> {
+        self.check_open()?;
+        let buffer = self.encode(&Message { event, data })?;
+        self.write(&buffer).map_err(|e| self.write_error(e))
     }
 
-    fn emit(&self, event: String, data: String) -> PyResult<()> {
-        let mut buffer = serde_json::to_vec(&Message { event, data }).unwrap();
-        buffer.push(0x4);
-        self.socket.borrow_mut().write_all(&buffer)?;
+    fn emit_unreliable(&self, event: String, data: Payload) -> PyResult<()> {
+        self.check_open()?;
+        let message = Message { event, data };
+        if !self.send_datagram(&message)? {
+            let buffer = self.encode(&message)?;
+            self.write(&buffer).map_err(|e| self.write_error(e))?;
+        }
         Ok(())
     }
+
+    #[pyo3(signature = (reason = "closed".to_string()))]
+    fn disconnect(&self, reason: String) {
+        self.close(&reason);
+    }
+
+    #[getter]
+    fn address(&self, py: Python) -> PyObject {
+        self.address.to_object(py)
+    }
+
+    #[getter]
+    fn node_id(&self) -> Option<String> {
+        self.remote
+            .borrow()
+            .as_ref()
+            .and_then(|remote| remote.id.clone())
+    }
+
+    #[getter]
+    fn app(&self) -> Option<String> {
+        self.remote
+            .borrow()
+            .as_ref()
+            .map(|remote| remote.app.clone())
+    }
+
+    #[getter]
+    fn version(&self) -> Option<String> {
+        self.remote
+            .borrow()
+            .as_ref()
+            .map(|remote| remote.version.clone())
+    }
+
+    #[getter]
+    fn features(&self) -> Vec<String> {
+        self.remote
+            .borrow()
+            .as_ref()
+            .map_or_else(Vec::new, |remote| remote.features.clone())
+    }
+
+    #[getter]
+    fn rooms(&self) -> Vec<String> {
+        let mut rooms: Vec<String> = self.rooms.borrow().iter().cloned().collect();
+        rooms.sort();
+        rooms
+    }
+
+    #[getter]
+    fn subscriptions(&self) -> Vec<String> {
+        self.subscriptions.borrow().clone()
+    }
+
+    #[getter]
+    fn disconnect_reason(&self) -> Option<String> {
+        self.disconnect_reason.borrow().clone()
+    }
+
+    #[getter]
+    fn certificate<'py>(&self, py: Python<'py>) -> Option<&'py PyBytes> {
+        self.tls
+            .borrow()
+            .as_ref()
+            .and_then(TlsStream::peer_certificate)
+            .map(|certificate| PyBytes::new(py, &certificate))
+    }
+
+    #[getter]
+    fn fingerprint(&self) -> Option<String> {
+        self.tls
+            .borrow()
+            .as_ref()
+            .and_then(TlsStream::peer_certificate)
+            .map(|certificate| tls::fingerprint(&certificate))
+    }
 }
 
 impl Peer {
     fn new(
         py: Python,
-        address: String,
-        socket: TcpStream,
-        tx: &Sender<ThreadMessage>,
+        network: &Network,
+        address: Address,
+        socket: Box<dyn Stream>,
+        tls: Option<TlsStream>,
+        origin: Origin,
     ) -> PyResult<Py<Self>> {
+        let config = &network.config;
+        let outbound = origin != Origin::Accepted;
+        let legacy = config.legacy || origin == Origin::Requested;
+        let (reader, writer) =
+            Self::streams(config, socket.as_ref(), tls.as_ref(), outbound, legacy)?;
+
+        // Datagrams are not encrypted, so they are only used without TLS.
+        let udp = network.udp.borrow().clone().filter(|_| tls.is_none());
+
         let peer = Py::new(
             py,
             Self {
-                name: address,
-                events: HashMap::new(),
-                socket: RefCell::new(socket.try_clone()?),
-                tx: tx.clone(),
+                name: address.to_string(),
+                address,
+                events: RefCell::default(),
+                socket: RefCell::new(socket),
+                writer: RefCell::new(writer),
+                tls: RefCell::new(tls),
+                udp: RefCell::new(udp),
+                token: handshake::random_u64(),
+                datagram_id: Cell::new(0),
+                config: config.clone(),
+                remote: RefCell::new(None),
+                connected: Cell::new(false),
+                closed: RefCell::new(None),
+                disconnect_reason: RefCell::new(None),
+                last_received: Cell::new(Instant::now()),
+                reconnecting: Cell::new(false),
+                goodbye: Cell::new(false),
+                reader: RefCell::new(None),
+                framing: Cell::new(if legacy {
+                    Framing::Legacy
+                } else {
+                    Framing::Binary
+                }),
+                legacy: Cell::new(legacy),
+                codec: Cell::new(Codec::Json),
+                origin,
+                nodes: network.nodes.clone(),
+                duplicate: RefCell::new(None),
+                rooms: RefCell::default(),
+                subscriptions: RefCell::default(),
+                tx: network.tx.clone().ok_or_else(|| {
+                    PyRuntimeError::new_err("serve() must be called before connecting")
+                })?,
             },
         )?;
-
-        let peer_clone: Py<Self> = peer.clone_ref(py);
-        thread::spawn(move || Self::listen(&peer_clone, socket));
-
-        tx.send(ThreadMessage {
-            event: "connect".to_string(),
-            peer: Some(peer.clone_ref(py)),
-            data: String::new(),
-        })
-        .unwrap();
-
+        Self::start(py, &peer, reader)?;
         Ok(peer)
     }
 
-    fn trigger(&self, py: Python, name: &str, data: String) -> PyResult<()> {
-        let args = PyTuple::new(py, [&data]);
-        if let Some(event) = self.events.get(name) {
-            event.borrow(py).call(py, args, None)?;
+    fn streams(
+        config: &Config,
+        socket: &dyn Stream,
+        tls: Option<&TlsStream>,
+        outbound: bool,
+        legacy: bool,
+    ) -> io::Result<(Reader, Writer)> {
+        if !legacy {
+            socket.set_read_timeout(Some(handshake::TIMEOUT))?;
         }
-        self.tx
-            .send(ThreadMessage {
-                event: name.to_string(),
-                peer: None,
-                data,
-            })
-            .unwrap();
-        Ok(())
+        socket.set_write_timeout(config.write_timeout)?;
+        let (reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) = match tls {
+            Some(tls) => (Box::new(tls.reader()?), Box::new(tls.clone())),
+            None => (Box::new(socket.try_clone()?), Box::new(socket.try_clone()?)),
+        };
+        let mut reader = FrameReader::new(reader);
+        if config.websocket && !outbound {
+            reader = reader.accept_websocket();
+        }
+        Ok((reader, Writer::spawn(writer, socket.try_clone()?)?))
     }
 
-    fn listen(peer: &Py<Self>, socket: TcpStream) {
-        let reader = BufReader::new(socket);
+    fn start(py: Python, peer: &Py<Self>, reader: Reader) -> PyResult<()> {
+        let slf = peer.borrow(py);
+        // Accepted peers only answer, so older clients never see frames they
+        // cannot read.
+        if slf.outbound() && !slf.legacy.get() {
+            slf.send_handshake()?;
+        }
 
-        for line in reader.split(0x4) {
-            if let Ok(buffer) = line {
-                Self::decode_message(peer, &buffer);
-            } else {
-                Python::with_gil(|py| {
-                    peer.borrow(py).tx.send(ThreadMessage {
-                        event: "disconnect".to_string(),
-                        peer: Some(peer.clone_ref(py)),
-                        data: String::new(),
-                    })
-                })
-                .unwrap();
-                break;
-            }
+        let peer_clone: Py<Self> = peer.clone_ref(py);
+        *slf.reader.borrow_mut() = Some(thread::spawn(move || Self::listen(&peer_clone, reader)));
+
+        if slf.legacy.get() {
+            slf.establish(py, peer);
         }
+        Ok(())
     }
 
-    fn decode_message(peer: &Py<Self>, buffer: &[u8]) {
-        let message: Message = if let Ok(message) = serde_json::from_slice(buffer) {
-            message
+    // Puts a new connection in place of one that dropped, so the peer keeps
+    // its Python object and handlers.
+    fn reattach(
+        py: Python,
+        peer: &Py<Self>,
+        socket: Box<dyn Stream>,
+        tls: Option<TlsStream>,
+    ) -> PyResult<()> {
+        let slf = peer.borrow(py);
+        let legacy = slf.legacy.get();
+        let (reader, writer) =
+            Self::streams(&slf.config, socket.as_ref(), tls.as_ref(), true, legacy)?;
+        *slf.socket.borrow_mut() = socket;
+        *slf.writer.borrow_mut() = writer;
+        *slf.tls.borrow_mut() = tls;
+        *slf.remote.borrow_mut() = None;
+        *slf.closed.borrow_mut() = None;
+        *slf.disconnect_reason.borrow_mut() = None;
+        *slf.duplicate.borrow_mut() = None;
+        slf.rooms.borrow_mut().clear();
+        slf.subscriptions.borrow_mut().clear();
+        slf.connected.set(false);
+        slf.last_received.set(Instant::now());
+        slf.framing.set(if legacy {
+            Framing::Legacy
         } else {
-            println!("Error: Malformed packet");
-            return;
-        };
+            Framing::Binary
+        });
+        slf.codec.set(Codec::Json);
+        slf.reconnecting.set(true);
+        slf.goodbye.set(false);
+        Self::start(py, peer, reader)
+    }
 
+    // Old clients dialed without being known as such never answer the
+    // handshake, so outbound connections that received nothing when it
+    // times out carry on in the old format instead.
+    fn fall_back_to_legacy(peer: &Py<Self>) -> bool {
         Python::with_gil(|py| {
-            if let Err(e) = peer.borrow(py).trigger(py, &message.event, message.data) {
-                println!("Error: {e}");
+            let slf = peer.borrow(py);
+            if !slf.outbound()
+                || slf.framing.get() != Framing::Binary
+                || slf.remote.borrow().is_some()
+            {
+                return false;
             }
-        });
+            // Ends the handshake the client read as the start of a message.
+            if let Err(e) = Frame::encode_legacy(&[]).and_then(|buffer| slf.write(&buffer)) {
+                slf.report(py, peer, e.into());
+                return false;
+            }
+            slf.framing.set(Framing::Legacy);
+            slf.legacy.set(true);
+            slf.establish(py, peer);
+            true
+        })
     }
-}
 
-#[pyclass]
-struct Network {
-    ip: String,
-    port: u16,
-    tx: Option<Sender<ThreadMessage>>,
-    events: HashMap<String, Py<Event>>,
-    peers: RefCell<Vec<Py<Peer>>>,
-}
+    fn outbound(&self) -> bool {
+        self.origin != Origin::Accepted
+    }
 
-#[pymethods]
-impl Network {
-    #[new]
-    fn new(ip: String, port: u16) -> Self {
-        Self {
-            ip,
-            port,
-            tx: None,
-            events: HashMap::new(),
-            peers: RefCell::new(Vec::new()),
+    fn encode(&self, message: &Message) -> io::Result<Vec<u8>> {
+        match self.framing.get() {
+            Framing::Binary => {
+                Frame::encode(FrameType::Message, &message.encode(self.codec.get())?)
+            }
+            Framing::Legacy => Frame::encode_legacy(&message.encode_legacy()?),
+            Framing::WebSocket => Ok(match message.data {
+                Payload::Binary(_) => {
+                    websocket::encode(Opcode::Binary, &message.encode(self.codec.get())?)
+                }
+                _ => websocket::encode(Opcode::Text, &message.encode_json()?),
+            }),
         }
     }
 
-    fn connect(&self, ip: &str, port: u16) -> PyResult<()> {
-        let socket = UdpSocket::bind("0.0.0.0:7337")?;
-        socket.send_to(&self.port.to_be_bytes(), (ip, port))?;
-        Ok(())
+    fn pong(&self, body: &[u8]) -> io::Result<()> {
+        let buffer = match self.framing.get() {
+            Framing::WebSocket => websocket::encode(Opcode::Pong, body),
+            _ => Frame::encode(FrameType::Pong, body)?,
+        };
+        self.write(&buffer)
     }
 
-    fn on(&mut self, py: Python, name: String) -> PyResult<Py<Event>> {
-        let event = Py::new(py, Event { callback: None })?;
-        self.events.insert(name, event.clone_ref(py));
-        Ok(event)
+    fn write(&self, buffer: &[u8]) -> io::Result<()> {
+        self.writer.borrow().write(buffer)
     }
 
-    fn emit(&self, py: Python, event: &str, data: &str) {
-        self.peers.borrow_mut().retain(|peer| {
-            peer.borrow(py)
-                .emit(event.to_string(), data.to_string())
-                .is_ok()
-        });
+    fn check_open(&self) -> PyResult<()> {
+        let reason = self.disconnect_reason.borrow().clone();
+        match reason.or_else(|| self.closed.borrow().clone()) {
+            Some(reason) => Err(PeerClosed::new_err(format!(
+                "Connection to {} is closed: {reason}",
+                self.name
+            ))),
+            None => Ok(()),
+        }
     }
 
-    #[pyo3(signature = (tcp = true, udp = true))]
-    fn serve(mut slf: PyRefMut<'_, Self>, py: Python, tcp: bool, udp: bool) {
-        let (tx, rx) = channel();
-        let ip = slf.ip.clone();
-        let port = slf.port;
-        slf.tx = Some(tx);
-        let network: Py<Self> = slf.into();
+    fn write_error(&self, e: io::Error) -> PyErr {
+        match e.kind() {
+            io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::UnexpectedEof => {
+                PeerClosed::new_err(format!("Connection to {} is closed: {e}", self.name))
+            }
+            _ => e.into(),
+        }
+    }
 
-        {
-            let slf = network.clone_ref(py);
-            thread::Builder::new()
-                .name("listen".to_string())
-                .spawn(move || Self::listen(&slf, rx))
-                .unwrap();
+    // Tells the peer why the connection ends before closing it, which ends
+    // the listen thread with a disconnect.
+    fn close(&self, reason: &str) {
+        self.goodbye.set(true);
+        self.disconnect_reason
+            .borrow_mut()
+            .get_or_insert_with(|| reason.to_string());
+        let goodbye = match self.framing.get() {
+            Framing::Binary => Frame::encode(FrameType::Goodbye, reason.as_bytes()).ok(),
+            Framing::WebSocket => Some(websocket::encode(Opcode::Close, &[])),
+            Framing::Legacy => None,
         };
-        if tcp {
-            let slf = network.clone_ref(py);
-            let ip = ip.clone();
-            thread::Builder::new()
-                .name("tcp_server".to_string())
-                .spawn(move || Self::tcp_server(&slf, ip, port))
-                .unwrap();
+        if let (true, Some(goodbye)) = (self.connected.get(), goodbye) {
+            self.write(&goodbye).ok();
+        }
+        self.writer.borrow().shutdown();
+        // The network cannot serve on its UDP port again while peers keep
+        // the socket.
+        self.udp.borrow_mut().take();
+    }
+
+    // Returns false when the peer has no UDP channel to send to.
+    fn send_datagram(&self, message: &Message) -> io::Result<bool> {
+        let Some((udp, address, token)) = self.datagram_target() else {
+            return Ok(false);
         };
-        if udp {
-            let slf = network.clone_ref(py);
-            thread::Builder::new()
-                .name("udp_server".to_string())
-                .spawn(move || Self::udp_server(&slf, ip, port))
-                .unwrap();
+
+        let id = self.datagram_id.get().wrapping_add(1);
+        self.datagram_id.set(id);
+        let fragments =
+            Datagram::split(token, id, &message.encode(self.codec.get())?).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Message too large for a datagram",
+                )
+            })?;
+        for fragment in fragments {
+            udp.send_to(&fragment, address)?;
+        }
+        Ok(true)
+    }
+
+    fn datagram_target(&self) -> Option<(Arc<UdpSocket>, SocketAddr, u64)> {
+        let udp = self.udp.borrow().clone()?;
+        let channel = self.remote.borrow().as_ref()?.udp?;
+        let mut address = self.address.socket_addr()?;
+        address.set_port(channel.port);
+        let address = address::reachable_from(udp.local_addr().ok()?, address);
+        Some((udp, address, channel.token))
+    }
+
+    fn send_handshake(&self) -> io::Result<()> {
+        let udp = match &*self.udp.borrow() {
+            Some(udp) => Some(UdpChannel {
+                port: udp.local_addr()?.port(),
+                token: self.token,
+            }),
+            None => None,
         };
+        let handshake = Handshake::new(
+            &self.config.app,
+            &self.config.id,
+            self.config.codec,
+            self.config.port,
+            udp,
+        );
+        let body = serde_json::to_vec(&handshake)?;
+        self.write(&Frame::encode(FrameType::Handshake, &body)?)
     }
-}
 
-impl Network {
-    fn listen(slf: &Py<Self>, rx: Receiver<ThreadMessage>) {
-        for message in rx {
-            Python::with_gil(|py| {
-                let slf = slf.borrow(py);
-                match message.event.as_str() {
-                    "connection_request" => {
-                        if let Some((ip, port)) = message.data.split_once(':') {
-                            if let Ok(port) = port.parse::<u16>() {
-                                slf.tcp_connect(py, ip, port).unwrap();
-                            }
-                        }
-                    }
-                    "connect" | "disconnect" => {
-                        if let Some(event) = slf.events.get(&message.event) {
-                            let args = PyTuple::new(py, &[message.peer]);
-                            event.borrow(py).call(py, args, None).unwrap();
-                        }
-                    }
-                    _ => {
-                        if let Some(event) = slf.events.get(&message.event) {
-                            let args = PyTuple::new(py, &[message.data]);
-                            event.borrow(py).call(py, args, None).unwrap();
-                        }
-                    }
-                }
-            });
+    fn establish(&self, py: Python, peer: &Py<Self>) {
+        self.connected.set(true);
+        if let Err(e) = self.socket.borrow().set_read_timeout(None) {
+            self.report(py, peer, e.into());
+        }
+        let remote = self.remote.borrow();
+        if let Some(id) = remote.as_ref().and_then(|remote| remote.id.clone()) {
+            transport::lock(&self.nodes).insert(id, peer.clone_ref(py));
+        }
+        let kind = if self.reconnecting.replace(false) {
+            Kind::Reconnected
+        } else {
+            Kind::Connect
+        };
+        self.tx
+            .send(ThreadMessage::new(kind, Some(peer.clone_ref(py))))
+            .ok();
+
+        // Peers that connected to us are introduced to our other peers,
+        // which connect to them in turn.
+        let port = remote.as_ref().and_then(|remote| remote.port);
+        if let (false, Some(port), Some(mut address)) =
+            (self.outbound(), port, self.address.socket_addr())
+        {
+            address.set_port(port);
+            self.tx
+                .send(ThreadMessage::new(
+                    Kind::Introduce(address),
+                    Some(peer.clone_ref(py)),
+                ))
+                .ok();
         }
     }
 
-    fn tcp_connect(&self, py: Python, ip: &str, port: u16) -> PyResult<()> {
-        let socket = TcpStream::connect((ip, port))?;
-        let peer = Peer::new(
-            py,
-            format!("{ip}:{port}"),
-            socket,
-            &self.tx.clone().unwrap(),
-        )?;
-        self.peers.borrow_mut().push(peer);
-        Ok(())
+    fn supports(&self, feature: &str) -> bool {
+        self.remote
+            .borrow()
+            .as_ref()
+            .is_some_and(|remote| remote.features.iter().any(|known| known == feature))
     }
 
-    fn tcp_server(slf: &Py<Self>, ip: String, port: u16) {
-        let listener = TcpListener::bind((ip, port)).unwrap();
+    // Where the peer accepts connections, as told to other members.
+    fn listen_address(&self) -> Option<SocketAddr> {
+        let mut address = self.address.socket_addr()?;
+        address.set_port(self.remote.borrow().as_ref()?.port?);
+        Some(address)
+    }
 
-        for stream in listener.incoming() {
-            let socket = match stream {
-                Ok(socket) => socket,
-                Err(_) => continue,
-            };
+    fn forget(&self, peer: &Py<Self>) {
+        let mut nodes = transport::lock(&self.nodes);
+        if let Some(id) = self.node_id() {
+            if nodes.get(&id).is_some_and(|known| known.is(peer)) {
+                nodes.remove(&id);
+            }
+        }
+    }
 
-            Python::with_gil(|py| {
-                let slf = slf.borrow(py);
+    // Connections between two networks are kept or dropped by the one with
+    // the lower node id, one at a time, so exactly one survives however
+    // many were opened. The other side waits for its decision before
+    // treating a connection as established.
+    fn arbiter(&self, remote: &Handshake) -> bool {
+        remote.id.as_ref().is_some_and(|id| self.config.id < *id)
+    }
 
-                Peer::new(
-                    py,
-                    socket.peer_addr().unwrap().to_string(),
-                    socket,
-                    &slf.tx.clone().unwrap(),
-                )
-                .map_or_else(
-                    |_| println!("Error: Failed to create peer"),
-                    |peer| slf.peers.borrow_mut().push(peer),
-                );
-            });
+    fn is_duplicate(&self, remote: &Handshake) -> bool {
+        let duplicate = self.arbiter(remote)
+            && remote
+                .id
+                .as_ref()
+                .is_some_and(|id| transport::lock(&self.nodes).contains_key(id));
+        if duplicate {
+            *self.duplicate.borrow_mut() = remote.id.clone();
         }
+        duplicate
     }
 
-    fn udp_server(slf: &Py<Self>, ip: String, port: u16) {
-        let socket = UdpSocket::bind((ip, port)).unwrap();
+    fn reject(&self, py: Python, peer: &Py<Self>, rejection: Rejection) {
+        let reason = match rejection {
+            Rejection::Local(reason) => {
+                let reject = Reject {
+                    reason: reason.clone(),
+                    duplicate: self
+                        .duplicate
+                        .borrow()
+                        .as_ref()
+                        .map(|_| self.config.id.clone()),
+                };
+                // The connection is dropped either way, the "rejected"
+                // event tells why.
+                serde_json::to_vec(&reject)
+                    .map_err(io::Error::from)
+                    .and_then(|body| Frame::encode(FrameType::Reject, &body))
+                    .and_then(|buffer| self.write(&buffer))
+                    .ok();
+                reason
+            }
+            Rejection::Remote(reason) => reason,
+        };
+
+        self.connected.set(false);
+        *self.closed.borrow_mut() = Some(reason.clone());
+        self.writer.borrow().shutdown();
+        self.tx
+            .send(ThreadMessage::new(
+                Kind::Rejected(reason),
+                Some(peer.clone_ref(py)),
+            ))
+            .ok();
+    }
 
-        loop {
-            let mut buffer = [0; 2];
-            let (_bytes_read, address) = match socket.recv_from(&mut buffer) {
-                Ok((bytes_read, address)) => (bytes_read, address),
+    // The handlers of the network run for the event even when the handler
+    // of the peer raised.
+    fn trigger(&self, py: Python, peer: &Py<Self>, message: Message) {
+        let value = match message.data.to_py(py) {
+            Ok(value) => value,
+            Err(e) => return self.report(py, peer, e),
+        };
+        let report = |e| {
+            if let Err(SendError(message)) = self.tx.send(ThreadMessage::exception(e)) {
+                if let Kind::Exception(error) = message.kind {
+                    excepthook(py, error, None);
+                }
+            }
+        };
+        if let Some(event) = handler(py, &self.events, &message.event) {
+            let args = PyTuple::new(py, [value.clone_ref(py)]);
+            Event::call(&event, py, args, report);
+        }
+        if let Some(event) = handler(py, &self.events, WILDCARD) {
+            let args = PyTuple::new(py, [message.event.to_object(py), value]);
+            Event::call(&event, py, args, report);
+        }
+        self.tx
+            .send(ThreadMessage::new(Kind::User(message), None))
+            .ok();
+    }
+
+    fn listen(peer: &Py<Self>, mut reader: Reader) {
+        let (rejection, reason) = loop {
+            match reader.read_frame() {
+                Ok(frame) => {
+                    if let Err(rejection) = Self::decode_frame(peer, &frame) {
+                        break (Some(rejection), "closed");
+                    }
+                }
                 Err(e) => {
-                    println!("Error: {e}");
-                    continue;
+                    let timeout = matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    );
+                    let (connected, timed_out) = Python::with_gil(|py| {
+                        let slf = peer.borrow(py);
+                        let timed_out = slf.writer.borrow().timed_out();
+                        (slf.connected.get(), timeout || timed_out)
+                    });
+                    if !connected && timeout && Self::fall_back_to_legacy(peer) {
+                        continue;
+                    }
+                    if !connected {
+                        break (
+                            Some(Rejection::Local(if timed_out {
+                                "Handshake timed out".to_string()
+                            } else {
+                                format!("Handshake failed: {e}")
+                            })),
+                            "closed",
+                        );
+                    }
+                    break match e.kind() {
+                        _ if timed_out => (None, "timeout"),
+                        io::ErrorKind::UnexpectedEof => (None, "closed"),
+                        _ => (None, "reset"),
+                    };
                 }
-            };
+            }
+        };
 
-            Python::with_gil(|py| {
-                let slf = slf.borrow(py);
-                let address = format!("{}", address.ip());
-                let port = u16::from_be_bytes(buffer);
-                slf.emit(py, "connection_request", &format!("{address}:{port}"));
-                slf.tcp_connect(py, address.as_str(), port).unwrap();
-            });
+        Python::with_gil(|py| {
+            let slf = peer.borrow(py);
+            slf.forget(peer);
+            match rejection {
+                Some(rejection) => slf.reject(py, peer, rejection),
+                None => {
+                    *slf.closed.borrow_mut() = Some("Connection closed".to_string());
+                    // The failure detector gives its own reason before it
+                    // shuts the socket down.
+                    slf.connected.set(false);
+                    slf.disconnect_reason
+                        .borrow_mut()
+                        .get_or_insert_with(|| reason.to_string());
+                    if slf.framing.get() == Framing::WebSocket {
+                        slf.write(&websocket::encode(Opcode::Close, &[])).ok();
+                        slf.writer.borrow().shutdown();
+                    }
+                    slf.tx
+                        .send(ThreadMessage::new(
+                            Kind::Disconnect,
+                            Some(peer.clone_ref(py)),
+                        ))
+                        .ok();
+                }
+            }
+        });
+    }
+
+    fn decode_frame(peer: &Py<Self>, frame: &Frame) -> Result<(), Rejection> {
+        let (connected, handshaken) = Python::with_gil(|py| {
+            let slf = peer.borrow(py);
+            slf.last_received.set(Instant::now());
+            let handshaken = slf.remote.borrow().is_some();
+            (slf.connected.get(), handshaken)
+        });
+
+        match frame.kind {
+            FrameType::Handshake if !connected && frame.framing == Framing::WebSocket => {
+                Self::upgrade(peer, frame)
+            }
+            FrameType::Handshake if !handshaken => Self::decode_handshake(peer, frame),
+            FrameType::Accept if !connected && handshaken => {
+                Python::with_gil(|py| peer.borrow(py).establish(py, peer));
+                Ok(())
+            }
+            FrameType::Reject => match serde_json::from_slice::<Reject>(&frame.body) {
+                Ok(reject) => Python::with_gil(|py| {
+                    *peer.borrow(py).duplicate.borrow_mut() = reject.duplicate;
+                    Err(Rejection::Remote(reject.reason))
+                }),
+                Err(_) => Err(Rejection::Remote("Rejected by peer".to_string())),
+            },
+            FrameType::Message if connected || frame.framing == Framing::Legacy => {
+                Self::decode_message(peer, frame);
+                Ok(())
+            }
+            FrameType::Members if connected => {
+                Self::pass_to_network(peer, Kind::Members, frame);
+                Ok(())
+            }
+            FrameType::Flood if connected => {
+                Self::pass_to_network(peer, Kind::Flood, frame);
+                Ok(())
+            }
+            FrameType::Routed if connected => {
+                Self::pass_to_network(peer, Kind::Routed, frame);
+                Ok(())
+            }
+            FrameType::Rooms if connected => {
+                Python::with_gil(|py| {
+                    let slf = peer.borrow(py);
+                    match rooms::decode(&frame.body) {
+                        Some(rooms) => *slf.rooms.borrow_mut() = rooms,
+                        None => slf.report(py, peer, ProtocolError::new_err("Malformed room list")),
+                    }
+                });
+                Ok(())
+            }
+            FrameType::Room if connected => {
+                Self::pass_to_network(peer, Kind::Room, frame);
+                Ok(())
+            }
+            FrameType::Subscriptions if connected => {
+                Python::with_gil(|py| {
+                    let slf = peer.borrow(py);
+                    match topics::decode(&frame.body) {
+                        Some(patterns) => *slf.subscriptions.borrow_mut() = patterns,
+                        None => slf.report(
+                            py,
+                            peer,
+                            ProtocolError::new_err("Malformed subscription list"),
+                        ),
+                    }
+                });
+                Ok(())
+            }
+            FrameType::Publish if connected => {
+                Self::pass_to_network(peer, Kind::Publish, frame);
+                Ok(())
+            }
+            // A peer that says goodbye is reconnected like any other that
+            // dropped, since it may only be restarting.
+            FrameType::Goodbye if connected => {
+                let reason = String::from_utf8_lossy(&frame.body);
+                Python::with_gil(|py| {
+                    let slf = peer.borrow(py);
+                    slf.disconnect_reason
+                        .borrow_mut()
+                        .get_or_insert_with(|| reason.to_string());
+                    slf.socket.borrow().shutdown().ok();
+                });
+                Ok(())
+            }
+            FrameType::Ping if connected => {
+                // Failed writes end the connection on this thread.
+                Python::with_gil(|py| peer.borrow(py).pong(&frame.body)).ok();
+                Ok(())
+            }
+            _ if !connected => Err(Rejection::Local("Expected a handshake".to_string())),
+            _ => Ok(()),
         }
     }
+
+    // Frames about the whole network are handled by its listen thread.
+    fn pass_to_network(peer: &Py<Self>, kind: fn(Vec<u8>) -> Kind, frame: &Frame) {
+        Python::with_gil(|py| {
+            peer.borrow(py)
+                .tx
+                .send(ThreadMessage::new(
+                    kind(frame.body.clone()),
+                    Some(peer.clone_ref(py)),
+                ))
+                .ok();
+        });
+    }
+
+    fn report(&self, py: Python, peer: &Py<Self>, error: PyErr) {
+        self.tx
+            .send(ThreadMessage::error(Some(peer.clone_ref(py)), error))
+            .ok();
+    }
+
+    fn decode_handshake(peer: &Py<Self>, frame: &Frame) -> Result<(), Rejection> {
+        let handshake: Handshake = serde_json::from_slice(&frame.body)
+            .map_err(|_| Rejection::Local("Malformed handshake".to_string()))?;
+
+        Python::with_gil(|py| {
+            let slf = peer.borrow(py);
+            handshake.check(&slf.config.app).map_err(Rejection::Local)?;
+            if handshake.id.as_ref() == Some(&slf.config.id) {
+                return Err(Rejection::Local("Connected to itself".to_string()));
+            }
+            if slf.is_duplicate(&handshake) {
+                return Err(Rejection::Local(DUPLICATE.to_string()));
+            }
+
+            if let Some(codec) = Codec::negotiate(slf.config.codec, &handshake.codecs) {
+                slf.codec.set(codec);
+            }
+            let arbiter = slf.arbiter(&handshake);
+            // Inbound connections from a node with a lower id wait for it
+            // to accept them.
+            let waits = !slf.outbound() && handshake.id.is_some() && !arbiter;
+            let handshake_failed = |e| Rejection::Local(format!("Handshake failed: {e}"));
+            if !slf.outbound() {
+                slf.send_handshake().map_err(handshake_failed)?;
+            } else if arbiter {
+                slf.write(&Frame::encode(FrameType::Accept, &[]).map_err(handshake_failed)?)
+                    .map_err(handshake_failed)?;
+            }
+            *slf.remote.borrow_mut() = Some(handshake);
+            if !waits {
+                slf.establish(py, peer);
+            }
+            Ok(())
+        })
+    }
+
+    // WebSocket clients skip the handshake, answering the upgrade request
+    // connects them.
+    fn upgrade(peer: &Py<Self>, frame: &Frame) -> Result<(), Rejection> {
+        let key = String::from_utf8_lossy(&frame.body);
+        Python::with_gil(|py| {
+            let slf = peer.borrow(py);
+            slf.write(&websocket::response(&key))
+                .map_err(|e| Rejection::Local(format!("Handshake failed: {e}")))?;
+            slf.framing.set(Framing::WebSocket);
+            slf.establish(py, peer);
+            Ok(())
+        })
+    }
+
+    fn decode_message(peer: &Py<Self>, frame: &Frame) {
+        let message = match frame.framing {
+            Framing::Binary => Message::decode(&frame.body),
+            Framing::Legacy => Message::decode_legacy(&frame.body),
+            Framing::WebSocket => Message::decode_json(&frame.body),
+        };
+        Python::with_gil(|py| {
+            let slf = peer.borrow(py);
+            let Ok(message) = message else {
+                slf.report(py, peer, ProtocolError::new_err("Malformed message"));
+                return;
+            };
+            if frame.framing == Framing::Legacy && slf.framing.get() != Framing::Legacy {
+                slf.framing.set(Framing::Legacy);
+                slf.legacy.set(true);
+                if !slf.connected.get() {
+                    slf.establish(py, peer);
+                }
+            }
+            if message.event == CONNECTION_REQUEST && !slf.supports(handshake::MEMBERS) {
+                // Older clients send the address as an "ip:port" string.
+                let address = match &message.data {
+                    Payload::Text(address) => address.parse().ok(),
+                    Payload::Object(value) => address::from_value(value),
+                    Payload::Binary(_) => None,
+                };
+                if let Some(address) = address {
+                    slf.tx
+                        .send(ThreadMessage::new(
+                            Kind::ConnectionRequest(address),
+                            Some(peer.clone_ref(py)),
+                        ))
+                        .ok();
+                }
+                return;
+            }
+            slf.trigger(py, peer, message);
+        });
+    }
+}
+
+#[pyclass]
+struct Room {
+    #[pyo3(get)]
+    name: String,
+    network: Py<Network>,
+}
+
+#[pymethods]
+impl Room {
+    fn on(&self, py: Python, name: String) -> PyResult<Py<Event>> {
+        let network = self.network.borrow(py);
+        let mut room_events = network.room_events.borrow_mut();
+        event(py, room_events.entry(self.name.clone()).or_default(), name)
+    }
+
+    fn emit(&self, py: Python, event: String, data: Payload) -> PyResult<()> {
+        let message = Message { event, data };
+        self.network.borrow(py).emit_room(py, &self.name, &message)
+    }
+}
+
+#[pyclass]
+struct Network {
+    ip: String,
+    port: u16,
+    transport: Transport,
+    config: Config,
+    tls: Option<Tls>,
+    udp: RefCell<Option<Arc<UdpSocket>>>,
+    tx: Option<Sender<ThreadMessage>>,
+    // Addresses to connect to are dialed one at a time on the dial thread.
+    dialer: RefCell<Option<Sender<(SocketAddr, Origin)>>>,
+    dialing: RefCell<HashSet<SocketAddr>>,
+    events: RefCell<HashMap<String, Py<Event>>>,
+    peers: RefCell<Vec<Py<Peer>>>,
+    nodes: Nodes,
+    members: RefCell<Members>,
+    seen: RefCell<SeenCache>,
+    joined: RefCell<HashSet<String>>,
+    // Handlers registered on rooms, by room and then by event.
+    room_events: RefCell<HashMap<String, HashMap<String, Py<Event>>>>,
+    // Handlers of the topic patterns this network subscribes to, in the
+    // order they were subscribed.
+    subscriptions: RefCell<Vec<(String, Py<Event>)>>,
+    heartbeat: Option<Heartbeat>,
+    reconnect: bool,
+    listener_address: Option<Address>,
+    excepthook: RefCell<Option<PyObject>>,
+    shutdown: Arc<Shutdown>,
+    threads: RefCell<Vec<JoinHandle<()>>>,
+}
+
+#[pymethods]
+impl Network {
+    #[new]
+    #[pyo3(signature = (
+        ip,
+        port = 0,
+        legacy = false,
+        codec = Codec::Json,
+        app = "tknetwork".to_string(),
+        tls = false,
+        certfile = None,
+        keyfile = None,
+        transport = Transport::Tcp,
+        node_id = None,
+        heartbeat = 1.0,
+        heartbeat_timeout = 5.0,
+        reconnect = true,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        ip: String,
+        port: u16,
+        legacy: bool,
+        codec: Codec,
+        app: String,
+        tls: bool,
+        certfile: Option<&str>,
+        keyfile: Option<&str>,
+        transport: Transport,
+        node_id: Option<String>,
+        heartbeat: f64,
+        heartbeat_timeout: f64,
+        reconnect: bool,
+    ) -> PyResult<Self> {
+        let heartbeat = match Duration::try_from_secs_f64(heartbeat) {
+            Ok(interval) if interval.is_zero() => None,
+            Ok(interval) => match Duration::try_from_secs_f64(heartbeat_timeout) {
+                Ok(timeout) if timeout > interval => Some(Heartbeat { interval, timeout }),
+                _ => {
+                    return Err(PyValueError::new_err(
+                        "heartbeat_timeout must be longer than heartbeat",
+                    ))
+                }
+            },
+            Err(_) => {
+                return Err(PyValueError::new_err(
+                    "heartbeat must be a positive number or 0",
+                ))
+            }
+        };
+        // Writes to a peer that stopped reading fail after the same time
+        // as a peer that stopped sending, whether or not it is pinged.
+        let write_timeout = Duration::try_from_secs_f64(heartbeat_timeout)
+            .ok()
+            .filter(|timeout| !timeout.is_zero());
+        let tls = if tls || certfile.is_some() || keyfile.is_some() {
+            Some(Tls::new(certfile, keyfile)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            ip,
+            port,
+            transport,
+            config: Config {
+                app,
+                legacy,
+                codec,
+                websocket: false,
+                port: None,
+                id: node_id.unwrap_or_else(handshake::random_node_id),
+                write_timeout,
+            },
+            tls,
+            udp: RefCell::new(None),
+            tx: None,
+            dialer: RefCell::new(None),
+            dialing: RefCell::default(),
+            events: RefCell::default(),
+            peers: RefCell::new(Vec::new()),
+            nodes: Arc::default(),
+            members: RefCell::default(),
+            seen: RefCell::default(),
+            joined: RefCell::default(),
+            room_events: RefCell::default(),
+            subscriptions: RefCell::default(),
+            heartbeat,
+            reconnect,
+            listener_address: None,
+            excepthook: RefCell::new(None),
+            shutdown: Arc::default(),
+            threads: RefCell::default(),
+        })
+    }
+
+    #[getter]
+    fn node_id(&self) -> String {
+        self.config.id.clone()
+    }
+
+    #[getter]
+    fn members(&self, py: Python) -> PyResult<Py<PyDict>> {
+        let members = PyDict::new(py);
+        for member in self.members.borrow().iter() {
+            let addresses: Vec<PyObject> = member
+                .addresses
+                .iter()
+                .map(|address| Address::ip(*address).to_object(py))
+                .collect();
+            let info = PyDict::new(py);
+            info.set_item("addresses", addresses)?;
+            info.set_item("last_seen", member.last_seen)?;
+            info.set_item("connected", self.is_connected(&member.id))?;
+            members.set_item(&member.id, info)?;
+        }
+        Ok(members.into())
+    }
+
+    #[getter]
+    fn rooms(&self) -> Vec<String> {
+        let mut rooms: Vec<String> = self.joined.borrow().iter().cloned().collect();
+        rooms.sort();
+        rooms
+    }
+
+    #[getter]
+    fn subscriptions(&self) -> Vec<String> {
+        self.subscriptions
+            .borrow()
+            .iter()
+            .map(|(pattern, _)| pattern.clone())
+            .collect()
+    }
+
+    // Called with the type, value and traceback of exceptions raised by
+    // handlers and of failures without an "error" handler, sys.excepthook
+    // when None.
+    #[getter]
+    fn excepthook(&self, py: Python) -> Option<PyObject> {
+        self.excepthook
+            .borrow()
+            .as_ref()
+            .map(|hook| hook.clone_ref(py))
+    }
+
+    #[setter]
+    fn set_excepthook(&self, hook: Option<PyObject>) {
+        *self.excepthook.borrow_mut() = hook;
+    }
+
+    // Keyed by "udp" and the transport of the listener. Serving on port 0
+    // shows the port that was picked here.
+    #[getter]
+    fn local_addresses(&self, py: Python) -> BTreeMap<&'static str, PyObject> {
+        let mut addresses = BTreeMap::new();
+        if self.shutdown.is_closed() {
+            return addresses;
+        }
+        if let Some(address) = &self.listener_address {
+            addresses.insert(self.transport.name(), address.to_object(py));
+        }
+        if let Some(Ok(address)) = self.udp.borrow().as_ref().map(|udp| udp.local_addr()) {
+            addresses.insert("udp", Address::ip(address).to_object(py));
+        }
+        addresses
+    }
+
+    #[getter]
+    fn fingerprint(&self) -> Option<String> {
+        self.tls
+            .as_ref()
+            .map(|tls| tls::fingerprint(tls.certificate()))
+    }
+
+    // The network is only borrowed between the blocking steps, so other
+    // threads can use it while this one waits.
+    #[pyo3(signature = (ip, port = 0, timeout = 10.0, retries = 0))]
+    fn connect(
+        slf: &PyCell<Self>,
+        py: Python,
+        ip: &str,
+        port: u16,
+        timeout: f64,
+        retries: u32,
+    ) -> PyResult<Py<Peer>> {
+        let timeout = Duration::try_from_secs_f64(timeout)
+            .map_err(|_| PyValueError::new_err("timeout must be a positive number"))?;
+        slf.borrow().check_serving()?;
+        let network: Py<Self> = slf.into();
+
+        let mut attempt = 0;
+        loop {
+            match Self::try_connect(&network, py, ip, port, timeout) {
+                Ok(peer) => return Ok(peer),
+                Err(Attempt::Retry(_)) if attempt < retries => {
+                    attempt += 1;
+                    py.allow_threads(|| thread::sleep(RETRY_DELAY));
+                }
+                Err(Attempt::Retry(e) | Attempt::Fail(e)) => return Err(e),
+            }
+        }
+    }
+
+    fn on(&self, py: Python, name: String) -> PyResult<Py<Event>> {
+        event(py, &mut self.events.borrow_mut(), name)
+    }
+
+    fn once(&self, py: Python, name: String) -> PyResult<Once> {
+        Ok(Once {
+            event: event(py, &mut self.events.borrow_mut(), name)?,
+        })
+    }
+
+    fn off(&self, py: Python, name: &str, handler: &PyAny) -> PyResult<()> {
+        remove_handler(py, &self.events, name, handler)
+    }
+
+    #[pyo3(signature = (event, data, flood = false, ttl = flood::DEFAULT_TTL))]
+    fn emit(&self, py: Python, event: String, data: Payload, flood: bool, ttl: u8) -> PyResult<()> {
+        let message = Message { event, data };
+        if flood {
+            if ttl == 0 {
+                return Err(PyValueError::new_err("ttl must be at least 1"));
+            }
+            let id = handshake::random_u64();
+            self.seen.borrow_mut().insert(id);
+            let encoded = message.encode(self.config.codec)?;
+            let flood = Flood {
+                id,
+                ttl,
+                origin: &self.config.id,
+                message: &encoded,
+            };
+            return self.flood(py, &flood, None, Some(&message));
+        }
+
+        self.peers.borrow_mut().retain(|peer| {
+            let peer = peer.borrow(py);
+            if !peer.connected.get() {
+                return true;
+            }
+            peer.encode(&message)
+                .map_or(true, |buffer| peer.write(&buffer).is_ok())
+        });
+        Ok(())
+    }
+
+    fn emit_unreliable(&self, py: Python, event: String, data: Payload) {
+        let message = Message { event, data };
+        self.peers.borrow_mut().retain(|peer| {
+            let peer = peer.borrow(py);
+            if !peer.connected.get() {
+                return true;
+            }
+            match peer.send_datagram(&message) {
+                Ok(true) => true,
+                _ => peer
+                    .encode(&message)
+                    .map_or(true, |buffer| peer.write(&buffer).is_ok()),
+            }
+        });
+    }
+
+    fn join(&self, py: Python, room: String) -> PyResult<()> {
+        check_room(&room)?;
+        if self.joined.borrow_mut().insert(room) {
+            self.announce_rooms(py, None);
+        }
+        Ok(())
+    }
+
+    fn leave(&self, py: Python, room: &str) {
+        if self.joined.borrow_mut().remove(room) {
+            self.announce_rooms(py, None);
+        }
+    }
+
+    fn room(slf: PyRef<'_, Self>, name: String) -> PyResult<Room> {
+        check_room(&name)?;
+        Ok(Room {
+            name,
+            network: slf.into(),
+        })
+    }
+
+    fn subscribe(&self, py: Python, pattern: String) -> PyResult<Py<Event>> {
+        if !topics::valid_pattern(&pattern) {
+            return Err(PyValueError::new_err(format!(
+                "Invalid topic pattern '{pattern}'"
+            )));
+        }
+        // Subscribing to a pattern again adds to its handlers, as on() does.
+        let mut subscriptions = self.subscriptions.borrow_mut();
+        if let Some((_, event)) = subscriptions.iter().find(|(known, _)| *known == pattern) {
+            return Ok(event.clone_ref(py));
+        }
+        if subscriptions.len() >= topics::MAX_SUBSCRIPTIONS {
+            return Err(PyValueError::new_err(format!(
+                "Cannot subscribe to more than {} patterns",
+                topics::MAX_SUBSCRIPTIONS
+            )));
+        }
+        let event = Py::new(py, Event::new())?;
+        subscriptions.push((pattern, event.clone_ref(py)));
+        drop(subscriptions);
+        self.announce_subscriptions(py, None);
+        Ok(event)
+    }
+
+    // With a handler only that function is removed, and the pattern stays
+    // subscribed while it has others.
+    #[pyo3(signature = (pattern, handler = None))]
+    fn unsubscribe(&self, py: Python, pattern: &str, handler: Option<&PyAny>) -> PyResult<()> {
+        if let Some(handler) = handler {
+            let event = self
+                .subscriptions
+                .borrow()
+                .iter()
+                .find(|(known, _)| known == pattern)
+                .map(|(_, event)| event.clone_ref(py));
+            let removed = match &event {
+                Some(event) => Event::remove(event, py, handler)?,
+                None => false,
+            };
+            if !removed {
+                return Err(PyValueError::new_err(format!(
+                    "Handler is not subscribed to '{pattern}'"
+                )));
+            }
+            if event.is_some_and(|event| !event.borrow(py).handlers.is_empty()) {
+                return Ok(());
+            }
+        }
+
+        let removed = {
+            let mut subscriptions = self.subscriptions.borrow_mut();
+            let count = subscriptions.len();
+            subscriptions.retain(|(known, _)| known != pattern);
+            subscriptions.len() != count
+        };
+        if removed {
+            self.announce_subscriptions(py, None);
+        }
+        Ok(())
+    }
+
+    fn publish(&self, py: Python, topic: String, data: Payload) -> PyResult<()> {
+        if !topics::valid_topic(&topic) {
+            return Err(PyValueError::new_err(format!("Invalid topic '{topic}'")));
+        }
+        let message = Message { event: topic, data };
+        let buffer = Frame::encode(FrameType::Publish, &message.encode(self.config.codec)?)?;
+        self.peers.borrow_mut().retain(|peer| {
+            let peer = peer.borrow(py);
+            let interested = peer
+                .subscriptions
+                .borrow()
+                .iter()
+                .any(|pattern| topics::matches(pattern, &message.event));
+            if !peer.connected.get() || !interested {
+                return true;
+            }
+            peer.write(&buffer).is_ok()
+        });
+        Ok(())
+    }
+
+    fn send_to(&self, py: Python, node_id: &str, event: String, data: Payload) -> PyResult<()> {
+        self.check_serving()?;
+        let message = Message { event, data };
+        if node_id == self.config.id {
+            self.deliver(py, node_id, message);
+            return Ok(());
+        }
+
+        let body = message.encode(self.config.codec)?;
+        let routed = Routed {
+            kind: routing::Kind::Message,
+            ttl: routing::DEFAULT_TTL,
+            destination: node_id,
+            origin: &self.config.id,
+            body: &body,
+        };
+        if let Err(reason) = self.route(py, &routed) {
+            self.undeliverable(py, &routed, reason);
+        }
+        Ok(())
+    }
+
+    #[pyo3(signature = (
+        tcp = true,
+        udp = true,
+        websocket = false,
+        discovery = false,
+        group = discovery::GROUP.to_string(),
+        advertise = false,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn serve(
+        mut slf: PyRefMut<'_, Self>,
+        py: Python,
+        tcp: bool,
+        udp: bool,
+        websocket: bool,
+        discovery: bool,
+        group: String,
+        advertise: bool,
+    ) -> PyResult<()> {
+        if slf.shutdown.is_closed() {
+            return Err(PyRuntimeError::new_err("Network is closed"));
+        }
+        let (tx, rx) = channel();
+        let (dialer, dial_rx) = channel();
+        let ip = slf.ip.clone();
+        let mut port = slf.port;
+        let listener = if tcp {
+            let listener = slf.transport.bind(&ip, port);
+            Some(listener.map_err(|e| bind_error(slf.transport.name(), &ip, port, &e))?)
+        } else {
+            None
+        };
+        // With port 0 the UDP socket takes the port the listener was given.
+        if let Some(Address::Ip(address)) = listener
+            .as_ref()
+            .and_then(|listener| listener.local_addr().ok())
+        {
+            port = address.port();
+            slf.config.port = Some(port);
+        }
+        let socket = if udp && slf.transport == Transport::Tcp {
+            let socket =
+                address::bind_udp(&ip, port).map_err(|e| bind_error("udp", &ip, port, &e))?;
+            Some(Arc::new(socket))
+        } else {
+            None
+        };
+        // Announcements point at the TCP port the listener was given.
+        let discovery = match (discovery, slf.config.port) {
+            (false, _) => None,
+            (true, Some(port)) => {
+                let interface = slf.interface();
+                let discovery =
+                    Discovery::bind(interface, &group, &slf.config.app, &slf.config.id, port);
+                Some(discovery.map_err(|e| bind_error("discovery", &group, discovery::PORT, &e))?)
+            }
+            (true, None) => return Err(PyValueError::new_err("discovery requires the tcp server")),
+        };
+        let advertiser = match (advertise, slf.config.port) {
+            (false, _) => None,
+            (true, Some(port)) => {
+                let label = format!("{}-{}", slf.config.app, slf.config.id);
+                let txt = vec![
+                    format!("app={}", slf.config.app),
+                    format!("version={}", env!("CARGO_PKG_VERSION")),
+                ];
+                let interface = slf.interface();
+                let advertiser = mdns::Advertiser::bind(interface, &label, port, txt);
+                Some(
+                    advertiser
+                        .map_err(|e| bind_error("mdns", &interface.to_string(), mdns::PORT, &e))?,
+                )
+            }
+            (true, None) => return Err(PyValueError::new_err("advertise requires the tcp server")),
+        };
+        // Nothing is kept until every socket is bound, so serve can be
+        // called again after it failed.
+        slf.udp = RefCell::new(socket.clone());
+        slf.tx = Some(tx.clone());
+        slf.dialer = RefCell::new(Some(dialer));
+        slf.config.websocket = websocket;
+        slf.listener_address = listener
+            .as_ref()
+            .and_then(|listener| listener.local_addr().ok());
+        let shutdown = slf.shutdown.clone();
+        let network: Py<Self> = slf.into();
+        let mut threads = Vec::new();
+
+        {
+            let slf = network.clone_ref(py);
+            threads.push(
+                thread::Builder::new()
+                    .name("listen".to_string())
+                    .spawn(move || Self::listen(&slf, rx))?,
+            );
+        };
+        {
+            let (slf, shutdown) = (network.clone_ref(py), shutdown.clone());
+            threads.push(
+                thread::Builder::new()
+                    .name("dial".to_string())
+                    .spawn(move || Self::dial_loop(&slf, dial_rx, &shutdown))?,
+            );
+        };
+        if let Some(listener) = listener {
+            let (slf, shutdown) = (network.clone_ref(py), shutdown.clone());
+            threads.push(
+                thread::Builder::new()
+                    .name("server".to_string())
+                    .spawn(move || Self::server(&slf, listener.as_ref(), &shutdown))?,
+            );
+        };
+        {
+            let (slf, shutdown) = (network.clone_ref(py), shutdown.clone());
+            threads.push(
+                thread::Builder::new()
+                    .name("members".to_string())
+                    .spawn(move || Self::members_loop(&slf, &shutdown))?,
+            );
+        };
+        if let Some(heartbeat) = network.borrow(py).heartbeat {
+            let (slf, shutdown) = (network.clone_ref(py), shutdown.clone());
+            threads.push(
+                thread::Builder::new()
+                    .name("heartbeat".to_string())
+                    .spawn(move || Self::heartbeat_loop(&slf, heartbeat, &shutdown))?,
+            );
+        };
+        if let Some(advertiser) = advertiser {
+            let (shutdown, tx) = (shutdown.clone(), tx.clone());
+            threads.push(
+                thread::Builder::new()
+                    .name("mdns".to_string())
+                    .spawn(move || {
+                        advertiser.run(&shutdown, |e| {
+                            let error = NetworkError::new_err(format!("mDNS failed: {e}"));
+                            tx.send(ThreadMessage::error(None, error)).ok();
+                        });
+                    })?,
+            );
+        };
+        if let Some(discovery) = discovery {
+            let (slf, shutdown, tx) = (network.clone_ref(py), shutdown.clone(), tx.clone());
+            threads.push(thread::Builder::new().name("discovery".to_string()).spawn(
+                move || {
+                    discovery.run(
+                        &shutdown,
+                        |e| {
+                            let error = NetworkError::new_err(format!("Discovery failed: {e}"));
+                            tx.send(ThreadMessage::error(None, error)).ok();
+                        },
+                        |address, dial| {
+                            Self::discovered(&slf, address::canonical(address), dial);
+                        },
+                    );
+                },
+            )?);
+        };
+        if let Some(socket) = socket {
+            let (slf, shutdown) = (network.clone_ref(py), shutdown.clone());
+            threads.push(
+                thread::Builder::new()
+                    .name("udp_server".to_string())
+                    .spawn(move || Self::udp_server(&slf, &socket, &shutdown))?,
+            );
+        };
+        network.borrow(py).threads.borrow_mut().extend(threads);
+
+        // Networks that are still open when the interpreter exits are closed
+        // first, so no thread runs into a finalized interpreter.
+        let close = network.getattr(py, "close")?;
+        py.import("atexit")?.call_method1("register", (close,))?;
+        Ok(())
+    }
+
+    // Stops accepting, says goodbye to every peer and waits for the threads
+    // of the network to end. Handlers still run for the peers that
+    // disconnect.
+    fn close(slf: PyRef<'_, Self>, py: Python) -> PyResult<()> {
+        if slf.shutdown.is_closed() {
+            return Ok(());
+        }
+        slf.shutdown.close();
+        if let Some(address) = &slf.listener_address {
+            transport::wake(address);
+        }
+        // The dial thread ends with the channel, once it is done with the
+        // address it is dialing.
+        slf.dialer.borrow_mut().take();
+        if let Some(udp) = slf.udp.borrow_mut().take() {
+            if let Ok(local) = udp.local_addr() {
+                udp.send_to(&[], address::loopback(local)).ok();
+            }
+        }
+
+        // A handler may close the network from one of the threads joined
+        // here, which cannot wait for itself.
+        let current = thread::current().id();
+        let peers: Vec<Py<Peer>> = slf.peers.borrow_mut().drain(..).collect();
+        let readers: Vec<JoinHandle<()>> = peers
+            .iter()
+            .filter_map(|peer| {
+                let peer = peer.borrow(py);
+                peer.close("closed");
+                let reader = peer.reader.borrow_mut().take();
+                reader
+            })
+            .filter(|reader| reader.thread().id() != current)
+            .collect();
+        py.allow_threads(|| {
+            for reader in readers {
+                reader.join().ok();
+            }
+        });
+
+        // The listen thread runs the disconnect handlers before it stops.
+        if let Some(tx) = &slf.tx {
+            tx.send(ThreadMessage::new(Kind::Close, None)).ok();
+        }
+        let threads: Vec<JoinHandle<()>> = slf.threads.borrow_mut().drain(..).collect();
+        py.allow_threads(|| {
+            for thread in threads {
+                if thread.thread().id() != current {
+                    thread.join().ok();
+                }
+            }
+        });
+        transport::lock(&slf.nodes).clear();
+        // Unix sockets leave their path behind, which would keep the next
+        // network from serving on it.
+        if let Some(Address::Unix(path)) = &slf.listener_address {
+            std::fs::remove_file(path).ok();
+        }
+
+        let close = slf.into_py(py).getattr(py, "close")?;
+        py.import("atexit")?.call_method1("unregister", (close,))?;
+        Ok(())
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        slf: PyRef<'_, Self>,
+        py: Python,
+        _exc_type: PyObject,
+        _exc_value: PyObject,
+        _traceback: PyObject,
+    ) -> PyResult<bool> {
+        Self::close(slf, py)?;
+        Ok(false)
+    }
+
+    #[pyo3(signature = (timeout = 1.0))]
+    fn browse(&self, py: Python, timeout: f64) -> PyResult<Vec<PyObject>> {
+        let interface = self.interface();
+        let timeout = Duration::try_from_secs_f64(timeout)
+            .map_err(|_| PyValueError::new_err("timeout must be a positive number"))?;
+        let services = py.allow_threads(|| mdns::browse(interface, timeout))?;
+        services
+            .into_iter()
+            .map(|service| {
+                let dict = PyDict::new(py);
+                dict.set_item("name", service.name)?;
+                dict.set_item("address", Address::ip(service.address).to_object(py))?;
+                dict.set_item("app", service.txt.get("app"))?;
+                dict.set_item("version", service.txt.get("version"))?;
+                Ok(dict.into())
+            })
+            .collect()
+    }
+}
+
+// Calls the hook with the type, value and traceback of the exception like
+// sys.excepthook, which is used when there is no hook. Exceptions raised by
+// the hook itself are printed.
+fn excepthook(py: Python, error: PyErr, hook: Option<PyObject>) {
+    let hook = match hook {
+        Some(hook) => Ok(hook),
+        None => py
+            .import("sys")
+            .and_then(|sys| sys.getattr("excepthook"))
+            .map(Into::into),
+    };
+    let args = (error.get_type(py), error.value(py), error.traceback(py));
+    if let Err(e) = hook.and_then(|hook| hook.call1(py, args)) {
+        e.print(py);
+    }
+}
+
+fn bind_error(transport: &str, ip: &str, port: u16, e: &io::Error) -> PyErr {
+    let address = match transport {
+        "unix" | "memory" => format!("{transport}:{ip}"),
+        _ => format!("{transport} {ip}:{port}"),
+    };
+    let message = format!("Could not bind {address}: {e}");
+    match e.raw_os_error() {
+        Some(errno) => BindError::new_err((errno, message)),
+        None => BindError::new_err(message),
+    }
+}
+
+fn check_room(name: &str) -> PyResult<()> {
+    if rooms::valid(name) {
+        Ok(())
+    } else {
+        Err(PyValueError::new_err(
+            "Room name must be between 1 and 255 bytes long",
+        ))
+    }
+}
+
+impl Network {
+    // mDNS runs over IPv4 on the interface the network is bound to, or on
+    // the default one.
+    fn interface(&self) -> Ipv4Addr {
+        self.ip.parse().unwrap_or(Ipv4Addr::UNSPECIFIED)
+    }
+
+    fn listen(network: &Py<Self>, rx: Receiver<ThreadMessage>) {
+        for message in rx {
+            let peer = message.peer;
+            let kind = match message.kind {
+                Kind::Close => break,
+                kind => kind,
+            };
+            Python::with_gil(|py| {
+                let slf = network.borrow(py);
+                match kind {
+                    Kind::User(message) => slf.receive(py, message, peer),
+                    Kind::ConnectionRequest(address) => slf.dial(address, Origin::Introduced),
+                    Kind::Introduce(address) => {
+                        slf.request_connections(py, address, peer.as_ref());
+                    }
+                    Kind::Discovered(address) => {
+                        if let Some(event) = slf.handler(py, "discovered") {
+                            let args = PyTuple::new(py, [Address::Ip(address).to_object(py)]);
+                            slf.call_handler(py, &event, args);
+                        }
+                    }
+                    Kind::Rejected(reason) => {
+                        if let Some(peer) = &peer {
+                            slf.peers.borrow_mut().retain(|other| !other.is(peer));
+                        }
+                        // Dropping a duplicate connection is not a failure,
+                        // the node stays connected through the other one.
+                        let duplicate = peer
+                            .as_ref()
+                            .is_some_and(|peer| peer.borrow(py).duplicate.borrow().is_some());
+                        if let Some(event) = slf.handler(py, "rejected").filter(|_| !duplicate) {
+                            let args = PyTuple::new(py, [peer.into_py(py), reason.into_py(py)]);
+                            slf.call_handler(py, &event, args);
+                        }
+                    }
+                    Kind::Flood(body) => slf.receive_flood(py, &body, peer.as_ref()),
+                    Kind::Routed(body) => slf.receive_routed(py, &body),
+                    Kind::Room(body) => slf.receive_room(py, &body),
+                    Kind::Publish(body) => slf.receive_publish(py, &body),
+                    Kind::Members(body) => match Members::decode(&body) {
+                        Some(members) => {
+                            let via = peer.and_then(|peer| peer.borrow(py).node_id());
+                            slf.merge_members(via.as_deref(), members);
+                        }
+                        None => slf.raise_error(
+                            py,
+                            ProtocolError::new_err("Malformed member list"),
+                            peer,
+                        ),
+                    },
+                    Kind::Connect | Kind::Reconnected => {
+                        let Some(peer) = peer else {
+                            return;
+                        };
+                        // The mesh learns about new peers right away, and
+                        // they learn about the rest of the mesh.
+                        slf.exchange_members(py);
+                        slf.announce_rooms(py, Some(&peer));
+                        slf.announce_subscriptions(py, Some(&peer));
+                        let name = match kind {
+                            Kind::Connect => "connect",
+                            _ => "reconnected",
+                        };
+                        if let Some(event) = slf.handler(py, name) {
+                            let args = PyTuple::new(py, [peer]);
+                            slf.call_handler(py, &event, args);
+                        }
+                    }
+                    Kind::Disconnect => {
+                        let Some(peer) = peer else {
+                            return;
+                        };
+                        slf.peers.borrow_mut().retain(|other| !other.is(&peer));
+                        if let Some(event) = slf.handler(py, "disconnect") {
+                            let args = PyTuple::new(py, [peer.clone_ref(py)]);
+                            slf.call_handler(py, &event, args);
+                        }
+                        // Peers dialed with Network.connect are dialed again
+                        // until they are back.
+                        let dropped = {
+                            let peer = peer.borrow(py);
+                            peer.origin == Origin::Connected && !peer.goodbye.get()
+                        };
+                        if slf.reconnect && dropped && !slf.shutdown.is_closed() {
+                            let (network, shutdown) = (network.clone_ref(py), slf.shutdown.clone());
+                            match thread::Builder::new()
+                                .name("reconnect".to_string())
+                                .spawn(move || Self::reconnect_loop(&network, &peer, &shutdown))
+                            {
+                                Ok(handle) => slf.threads.borrow_mut().push(handle),
+                                Err(e) => slf.raise_error(py, e.into(), None),
+                            }
+                        }
+                    }
+                    Kind::Reconnecting(attempt) => {
+                        if let (Some(event), Some(peer)) = (slf.handler(py, "reconnecting"), peer) {
+                            let args = PyTuple::new(py, [peer.into_py(py), attempt.into_py(py)]);
+                            slf.call_handler(py, &event, args);
+                        }
+                    }
+                    Kind::Error(error) => slf.raise_error(py, error, peer),
+                    Kind::Exception(error) => slf.handle_exception(py, error),
+                    Kind::Close => {}
+                }
+            });
+        }
+    }
+
+    // Runs the handlers of an event emitted by a peer, then the wildcard
+    // handlers with its name.
+    fn receive(&self, py: Python, message: Message, peer: Option<Py<Peer>>) {
+        if RESERVED.contains(&message.event.as_str()) {
+            return;
+        }
+        let event = self.handler(py, &message.event);
+        let wildcard = self.handler(py, WILDCARD);
+        if event.is_none() && wildcard.is_none() {
+            return;
+        }
+        let data = match message.data.to_py(py) {
+            Ok(data) => data,
+            Err(e) => return self.raise_error(py, e, peer),
+        };
+        if let Some(event) = event {
+            let args = PyTuple::new(py, [data.clone_ref(py)]);
+            self.call_handler(py, &event, args);
+        }
+        if let Some(event) = wildcard {
+            let args = PyTuple::new(py, [message.event.to_object(py), data]);
+            self.call_handler(py, &event, args);
+        }
+    }
+
+    // Connects and waits until the handshake is done. Failures that may
+    // pass, such as refused connections and timeouts, can be retried.
+    fn try_connect(
+        network: &Py<Self>,
+        py: Python,
+        ip: &str,
+        port: u16,
+        timeout: Duration,
+    ) -> Result<Py<Peer>, Attempt> {
+        let deadline = Instant::now() + timeout;
+        let transport = network.borrow(py).transport;
+        let (socket, address) = py
+            .allow_threads(|| transport.connect(ip, port, timeout))
+            .map_err(|e| {
+                Attempt::Retry(ConnectError::new_err(format!(
+                    "Could not connect to {ip}: {e}"
+                )))
+            })?;
+        let name = address.to_string();
+        let slf = network.borrow(py);
+        slf.check_serving().map_err(Attempt::Fail)?;
+        let peer = slf
+            .add_peer(py, socket, address, Origin::Connected)
+            .map_err(Attempt::Fail)?;
+        let nodes = slf.nodes.clone();
+        drop(slf);
+        Self::await_established(py, &nodes, &peer, &name, deadline)
+    }
+
+    // Dials the address of a peer that dropped, and waits until the new
+    // connection is established in its place.
+    fn try_reconnect(network: &Py<Self>, py: Python, peer: &Py<Peer>) -> Result<Py<Peer>, Attempt> {
+        let deadline = Instant::now() + handshake::TIMEOUT;
+        let address = peer.borrow(py).address.clone();
+        let name = address.to_string();
+        let socket = py
+            .allow_threads(|| transport::reconnect(&address, handshake::TIMEOUT))
+            .map_err(|e| {
+                Attempt::Retry(ConnectError::new_err(format!(
+                    "Could not connect to {name}: {e}"
+                )))
+            })?;
+        let slf = network.borrow(py);
+        if slf.shutdown.is_closed() {
+            return Err(Attempt::Fail(PyRuntimeError::new_err("Network is closed")));
+        }
+        let tls = slf
+            .tls
+            .as_ref()
+            .map(|tls| tls.connect(socket.as_ref()))
+            .transpose()
+            .map_err(|e| Attempt::Retry(e.into()))?;
+        Peer::reattach(py, peer, socket, tls).map_err(Attempt::Retry)?;
+        slf.peers.borrow_mut().push(peer.clone_ref(py));
+        let nodes = slf.nodes.clone();
+        drop(slf);
+        Self::await_established(py, &nodes, peer, &name, deadline)
+    }
+
+    fn await_established(
+        py: Python,
+        nodes: &Nodes,
+        peer: &Py<Peer>,
+        name: &str,
+        deadline: Instant,
+    ) -> Result<Py<Peer>, Attempt> {
+        loop {
+            {
+                let slf = peer.borrow(py);
+                if slf.connected.get() {
+                    return Ok(peer.clone_ref(py));
+                }
+                // Connecting to a node we are already connected to gives
+                // the existing peer, which may still be waiting to be
+                // accepted on our side.
+                let duplicate = slf.duplicate.borrow().clone();
+                if let Some(id) = duplicate {
+                    let existing = transport::lock(nodes)
+                        .get(&id)
+                        .map(|existing| existing.clone_ref(py));
+                    if let Some(existing) = existing {
+                        return Ok(existing);
+                    }
+                } else if let Some(reason) = slf.closed.borrow().as_ref() {
+                    return Err(Attempt::Fail(ProtocolError::new_err(format!(
+                        "Connection to {name} failed: {reason}"
+                    ))));
+                }
+                if Instant::now() >= deadline {
+                    slf.socket.borrow().shutdown().ok();
+                    return Err(Attempt::Retry(Timeout::new_err(format!(
+                        "Connection to {name} timed out"
+                    ))));
+                }
+            }
+            py.allow_threads(|| thread::sleep(POLL_INTERVAL));
+        }
+    }
+
+    // Waits twice as long after every failed attempt, up to a limit, and a
+    // random part of that so peers that dropped together spread out.
+    fn reconnect_loop(network: &Py<Self>, peer: &Py<Peer>, shutdown: &Shutdown) {
+        let mut delay = RECONNECT_DELAY;
+        for attempt in 1u64.. {
+            let jitter = 0.5 + handshake::random_u64() as f64 / u64::MAX as f64 / 2.0;
+            if shutdown.wait(delay.mul_f64(jitter)) {
+                return;
+            }
+            let done = Python::with_gil(|py| {
+                {
+                    let slf = network.borrow(py);
+                    // The node may have come back over a connection it
+                    // opened.
+                    let id = peer.borrow(py).node_id();
+                    if id.is_some_and(|id| slf.is_connected(&id)) {
+                        return true;
+                    }
+                    if let Some(tx) = &slf.tx {
+                        tx.send(ThreadMessage::new(
+                            Kind::Reconnecting(attempt),
+                            Some(peer.clone_ref(py)),
+                        ))
+                        .ok();
+                    }
+                }
+                Self::try_reconnect(network, py, peer).is_ok()
+            });
+            if done {
+                return;
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    // Connects on the dial thread, so the threads handling messages never
+    // wait for a connection. Addresses already waiting are not queued again.
+    fn dial(&self, address: SocketAddr, origin: Origin) {
+        if let Some(dialer) = &*self.dialer.borrow() {
+            if self.dialing.borrow_mut().insert(address) {
+                dialer.send((address, origin)).ok();
+            }
+        }
+    }
+
+    // Failures are raised to the "error" handler by the listen thread.
+    fn dial_loop(network: &Py<Self>, rx: Receiver<(SocketAddr, Origin)>, shutdown: &Shutdown) {
+        for (address, origin) in rx {
+            if shutdown.is_closed() {
+                break;
+            }
+            Python::with_gil(|py| {
+                let result = Self::dial_ip(network, py, address, origin);
+                let slf = network.borrow(py);
+                slf.dialing.borrow_mut().remove(&address);
+                if let Err(e) = result {
+                    slf.report(None, e);
+                }
+            });
+        }
+    }
+
+    // The network is not borrowed while the connection is opened, and may
+    // have been closed in the meantime.
+    fn dial_ip(
+        network: &Py<Self>,
+        py: Python,
+        address: SocketAddr,
+        origin: Origin,
+    ) -> PyResult<Py<Peer>> {
+        network.borrow(py).check_serving()?;
+        let (socket, address) = py
+            .allow_threads(|| transport::connect_ip(address, handshake::TIMEOUT))
+            .map_err(|e| ConnectError::new_err(format!("Could not connect to {address}: {e}")))?;
+        let slf = network.borrow(py);
+        slf.check_serving()?;
+        slf.add_peer(py, socket, address, origin)
+    }
+
+    fn check_serving(&self) -> PyResult<()> {
+        if self.shutdown.is_closed() {
+            return Err(PyRuntimeError::new_err("Network is closed"));
+        }
+        if self.tx.is_none() {
+            return Err(PyRuntimeError::new_err(
+                "serve() must be called before connecting",
+            ));
+        }
+        Ok(())
+    }
+
+    fn add_peer(
+        &self,
+        py: Python,
+        socket: Box<dyn Stream>,
+        address: Address,
+        origin: Origin,
+    ) -> PyResult<Py<Peer>> {
+        let tls = self
+            .tls
+            .as_ref()
+            .map(|tls| tls.connect(socket.as_ref()))
+            .transpose()?;
+        let peer = Peer::new(py, self, address, socket, tls, origin)?;
+        self.peers.borrow_mut().push(peer.clone_ref(py));
+        Ok(peer)
+    }
+
+    fn server(slf: &Py<Self>, listener: &dyn Listener, shutdown: &Shutdown) {
+        loop {
+            let accepted = listener.accept();
+            // Network.close wakes the listener with a connection of its own.
+            if shutdown.is_closed() {
+                break;
+            }
+            let (socket, address) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => break,
+                Err(_) => continue,
+            };
+
+            Python::with_gil(|py| {
+                let slf = slf.borrow(py);
+
+                let tls = match slf
+                    .tls
+                    .as_ref()
+                    .map(|tls| tls.accept(socket.as_ref()))
+                    .transpose()
+                {
+                    Ok(tls) => tls,
+                    Err(e) => {
+                        let error =
+                            ProtocolError::new_err(format!("TLS with {address} failed: {e}"));
+                        slf.report(None, error);
+                        return;
+                    }
+                };
+
+                match Peer::new(py, &slf, address, socket, tls, Origin::Accepted) {
+                    Ok(peer) => slf.peers.borrow_mut().push(peer),
+                    Err(e) => slf.report(None, e),
+                }
+            });
+        }
+    }
+
+    fn udp_server(slf: &Py<Self>, socket: &UdpSocket, shutdown: &Shutdown) {
+        let mut reassembler = Reassembler::default();
+        let mut buffer = vec![0; u16::MAX as usize];
+
+        loop {
+            let received = socket.recv_from(&mut buffer);
+            // Network.close wakes the socket with a datagram of its own.
+            if shutdown.is_closed() {
+                break;
+            }
+            let (bytes_read, address) = match received {
+                Ok((bytes_read, address)) => (bytes_read, address),
+                Err(e) => {
+                    Python::with_gil(|py| slf.borrow(py).report(None, e.into()));
+                    continue;
+                }
+            };
+
+            if bytes_read != 2 {
+                // Only fragments from peers take up room until their message
+                // is complete.
+                let Some(datagram) = Datagram::parse(&buffer[..bytes_read]) else {
+                    continue;
+                };
+                let Some(peer) = Self::datagram_peer(slf, datagram.token) else {
+                    continue;
+                };
+                if let Some(body) = reassembler.insert(&datagram) {
+                    Self::decode_datagram(slf, &peer, &body);
+                }
+                continue;
+            }
+
+            Python::with_gil(|py| {
+                let slf = slf.borrow(py);
+                let port = u16::from_be_bytes([buffer[0], buffer[1]]);
+                let address = address::canonical(SocketAddr::new(address.ip(), port));
+                slf.request_connections(py, address, None);
+                // Only old clients join this way.
+                slf.dial(address, Origin::Requested);
+            });
+        }
+    }
+
+    fn discovered(slf: &Py<Self>, address: SocketAddr, dial: bool) {
+        Python::with_gil(|py| {
+            let slf = slf.borrow(py);
+            if let Some(tx) = &slf.tx {
+                tx.send(ThreadMessage::new(Kind::Discovered(address), None))
+                    .ok();
+            }
+            if dial {
+                slf.dial(address, Origin::Introduced);
+            }
+        });
+    }
+
+    // Peers that cannot relay floods get the message directly from the
+    // network that emitted it, relays only pass it to peers that can.
+    fn flood(
+        &self,
+        py: Python,
+        flood: &Flood,
+        except: Option<&Py<Peer>>,
+        message: Option<&Message>,
+    ) -> PyResult<()> {
+        let body = flood
+            .encode()
+            .ok_or_else(|| PyValueError::new_err("Node id is too long to flood messages"))?;
+        let buffer = Frame::encode(FrameType::Flood, &body)?;
+        self.peers.borrow_mut().retain(|peer| {
+            if except.is_some_and(|except| except.is(peer)) {
+                return true;
+            }
+            let peer = peer.borrow(py);
+            if !peer.connected.get() {
+                return true;
+            }
+            if peer.supports(handshake::FLOOD) {
+                peer.write(&buffer).is_ok()
+            } else if let Some(message) = message {
+                peer.encode(message)
+                    .map_or(true, |buffer| peer.write(&buffer).is_ok())
+            } else {
+                true
+            }
+        });
+        Ok(())
+    }
+
+    // Handlers run for the first copy of a message to arrive, and only that
+    // copy is relayed.
+    fn receive_flood(&self, py: Python, body: &[u8], from: Option<&Py<Peer>>) {
+        let Some(flood) = Flood::parse(body) else {
+            self.raise_error(
+                py,
+                ProtocolError::new_err("Malformed flood message"),
+                from.map(|peer| peer.clone_ref(py)),
+            );
+            return;
+        };
+        if flood.origin == self.config.id || !self.seen.borrow_mut().insert(flood.id) {
+            return;
+        }
+        if flood.ttl > 1 {
+            let relayed = Flood {
+                ttl: flood.ttl - 1,
+                ..flood
+            };
+            if let Err(e) = self.flood(py, &relayed, from, None) {
+                self.raise_error(py, e, None);
+            }
+        }
+
+        match Message::decode(flood.message) {
+            Ok(message) => self.deliver(py, flood.origin, message),
+            Err(_) => self.raise_error(py, ProtocolError::new_err("Malformed flood message"), None),
+        }
+    }
+
+    // Handlers registered on the peer that sent a message from further away
+    // run when it is connected directly, otherwise the network's handlers.
+    fn deliver(&self, py: Python, origin: &str, message: Message) {
+        let origin = transport::lock(&self.nodes)
+            .get(origin)
+            .map(|peer| peer.clone_ref(py));
+        match (origin, &self.tx) {
+            (Some(origin), _) => origin.borrow(py).trigger(py, &origin, message),
+            (None, Some(tx)) => {
+                tx.send(ThreadMessage::new(Kind::User(message), None)).ok();
+            }
+            (None, None) => {}
+        }
+    }
+
+    // Sends a routed message to its destination when it is a peer, or to
+    // the peer on the shortest route to it. Peers of older versions only
+    // receive messages meant for them, as plain messages.
+    fn route(&self, py: Python, routed: &Routed) -> Result<(), String> {
+        let next = {
+            let nodes = transport::lock(&self.nodes);
+            let routing = |id: &str| {
+                nodes
+                    .get(id)
+                    .is_some_and(|peer| peer.borrow(py).supports(handshake::ROUTING))
+            };
+            let via = match nodes.get(routed.destination) {
+                Some(_) => Some(routed.destination.to_string()),
+                None => self.members.borrow().next_hop(routed.destination, routing),
+            };
+            via.and_then(|id| nodes.get(&id).map(|peer| peer.clone_ref(py)))
+        };
+        let peer = next.ok_or_else(|| "No route to node".to_string())?;
+        let peer = peer.borrow(py);
+
+        let buffer = if peer.supports(handshake::ROUTING) {
+            let body = routed
+                .encode()
+                .ok_or_else(|| "Node id is too long to route messages".to_string())?;
+            Frame::encode(FrameType::Routed, &body)
+        } else if routed.kind == routing::Kind::Message {
+            Message::decode(routed.body).and_then(|message| peer.encode(&message))
+        } else {
+            return Ok(());
+        };
+        buffer
+            .and_then(|buffer| peer.write(&buffer))
+            .map_err(|e| e.to_string())
+    }
+
+    fn receive_routed(&self, py: Python, body: &[u8]) {
+        let Some(routed) = Routed::parse(body) else {
+            self.raise_error(py, ProtocolError::new_err("Malformed routed message"), None);
+            return;
+        };
+        if routed.destination != self.config.id {
+            let result = if routed.ttl > 1 {
+                let forwarded = Routed {
+                    ttl: routed.ttl - 1,
+                    ..routed
+                };
+                self.route(py, &forwarded)
+            } else {
+                Err("Hop limit reached".to_string())
+            };
+            if let Err(reason) = result {
+                self.undeliverable(py, &routed, reason);
+            }
+            return;
+        }
+
+        match routed.kind {
+            routing::Kind::Message => match Message::decode(routed.body) {
+                Ok(message) => self.deliver(py, routed.origin, message),
+                Err(_) => {
+                    self.raise_error(py, ProtocolError::new_err("Malformed routed message"), None);
+                }
+            },
+            routing::Kind::Undeliverable => {
+                match serde_json::from_slice::<Undeliverable>(routed.body) {
+                    Ok(undeliverable) => self.report_undeliverable(py, &undeliverable),
+                    Err(_) => {
+                        let error = ProtocolError::new_err("Malformed routed message");
+                        self.raise_error(py, error, None);
+                    }
+                }
+            }
+        }
+    }
+
+    // Tells the network that sent a message it did not arrive. Reports about
+    // other reports are dropped, so they never go back and forth.
+    fn undeliverable(&self, py: Python, routed: &Routed, reason: String) {
+        if routed.kind != routing::Kind::Message {
+            return;
+        }
+        let undeliverable = Undeliverable {
+            node_id: routed.destination.to_string(),
+            event: Message::decode(routed.body)
+                .map(|message| message.event)
+                .unwrap_or_default(),
+            reason,
+        };
+        if routed.origin == self.config.id {
+            self.report_undeliverable(py, &undeliverable);
+            return;
+        }
+        let Ok(body) = serde_json::to_vec(&undeliverable) else {
+            return;
+        };
+        let report = Routed {
+            kind: routing::Kind::Undeliverable,
+            ttl: routing::DEFAULT_TTL,
+            destination: routed.origin,
+            origin: &self.config.id,
+            body: &body,
+        };
+        // The origin cannot be told, and nobody else needs to know.
+        self.route(py, &report).ok();
+    }
+
+    // Raised as a ConnectError with the node_id, event and reason of the
+    // message that was not delivered.
+    fn report_undeliverable(&self, py: Python, undeliverable: &Undeliverable) {
+        let error = ConnectError::new_err(format!(
+            "Could not deliver '{}' to {}: {}",
+            undeliverable.event, undeliverable.node_id, undeliverable.reason
+        ));
+        let value = error.value(py);
+        for (name, field) in [
+            ("node_id", &undeliverable.node_id),
+            ("event", &undeliverable.event),
+            ("reason", &undeliverable.reason),
+        ] {
+            value.setattr(name, field).ok();
+        }
+        self.report(None, error);
+    }
+
+    // Runs the "error" handler with the exception, which has the peer it is
+    // about as its peer attribute. Without a handler it goes to the
+    // excepthook.
+    fn raise_error(&self, py: Python, error: PyErr, peer: Option<Py<Peer>>) {
+        error.value(py).setattr("peer", peer).ok();
+        match self.handler(py, "error") {
+            Some(event) => {
+                let args = PyTuple::new(py, [error.into_py(py)]);
+                self.call_handler(py, &event, args);
+            }
+            None => self.handle_exception(py, error),
+        }
+    }
+
+    fn handler(&self, py: Python, name: &str) -> Option<Py<Event>> {
+        handler(py, &self.events, name)
+    }
+
+    // A handler that raises does not keep the others from running.
+    fn call_handler(&self, py: Python, event: &Py<Event>, args: &PyTuple) {
+        Event::call(event, py, args, |e| self.handle_exception(py, e));
+    }
+
+    fn handle_exception(&self, py: Python, error: PyErr) {
+        let hook = self
+            .excepthook
+            .borrow()
+            .as_ref()
+            .map(|hook| hook.clone_ref(py));
+        excepthook(py, error, hook);
+    }
+
+    fn report(&self, peer: Option<Py<Peer>>, error: PyErr) {
+        if let Some(tx) = &self.tx {
+            tx.send(ThreadMessage::error(peer, error)).ok();
+        }
+    }
+
+    // Tells peers which rooms this network joined, either all of them after
+    // a change or a single peer that just connected.
+    fn announce_rooms(&self, py: Python, only: Option<&Py<Peer>>) {
+        let buffer = rooms::encode(&self.joined.borrow())
+            .map_err(io::Error::from)
+            .and_then(|body| Frame::encode(FrameType::Rooms, &body));
+        let buffer = match buffer {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                self.report(None, e.into());
+                return;
+            }
+        };
+        self.peers.borrow_mut().retain(|peer| {
+            if only.is_some_and(|only| !only.is(peer)) {
+                return true;
+            }
+            let peer = peer.borrow(py);
+            if !peer.connected.get() || !peer.supports(handshake::ROOMS) {
+                return true;
+            }
+            peer.write(&buffer).is_ok()
+        });
+    }
+
+    // Only peers that joined the room receive its messages, whether or not
+    // this network joined it.
+    fn emit_room(&self, py: Python, room: &str, message: &Message) -> PyResult<()> {
+        let encoded = message.encode(self.config.codec)?;
+        let body = RoomMessage {
+            room,
+            message: &encoded,
+        }
+        .encode()
+        .ok_or_else(|| PyValueError::new_err("Room name is too long"))?;
+        let buffer = Frame::encode(FrameType::Room, &body)?;
+        self.peers.borrow_mut().retain(|peer| {
+            let peer = peer.borrow(py);
+            if !peer.connected.get() || !peer.rooms.borrow().contains(room) {
+                return true;
+            }
+            peer.write(&buffer).is_ok()
+        });
+        Ok(())
+    }
+
+    fn receive_room(&self, py: Python, body: &[u8]) {
+        let Some(room) = RoomMessage::parse(body) else {
+            self.raise_error(py, ProtocolError::new_err("Malformed room message"), None);
+            return;
+        };
+        // Messages sent before a peer learned that we left are dropped.
+        if !self.joined.borrow().contains(room.room) {
+            return;
+        }
+        let Ok(message) = Message::decode(room.message) else {
+            self.raise_error(py, ProtocolError::new_err("Malformed room message"), None);
+            return;
+        };
+        let event = self
+            .room_events
+            .borrow()
+            .get(room.room)
+            .and_then(|events| events.get(&message.event))
+            .map(|event| event.clone_ref(py));
+        if let Some(event) = event {
+            match message.data.to_py(py) {
+                Ok(data) => {
+                    let args = PyTuple::new(py, [data]);
+                    self.call_handler(py, &event, args);
+                }
+                Err(e) => self.raise_error(py, e, None),
+            }
+        }
+    }
+
+    // Tells peers which topics to publish to this network, either all of
+    // them after a change or a single peer that just connected.
+    fn announce_subscriptions(&self, py: Python, only: Option<&Py<Peer>>) {
+        let subscriptions = self.subscriptions.borrow();
+        let patterns: Vec<&str> = subscriptions
+            .iter()
+            .map(|(pattern, _)| pattern.as_str())
+            .collect();
+        let buffer = topics::encode(&patterns)
+            .map_err(io::Error::from)
+            .and_then(|body| Frame::encode(FrameType::Subscriptions, &body));
+        drop(subscriptions);
+        let buffer = match buffer {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                self.report(None, e.into());
+                return;
+            }
+        };
+        self.peers.borrow_mut().retain(|peer| {
+            if only.is_some_and(|only| !only.is(peer)) {
+                return true;
+            }
+            let peer = peer.borrow(py);
+            if !peer.connected.get() || !peer.supports(handshake::TOPICS) {
+                return true;
+            }
+            peer.write(&buffer).is_ok()
+        });
+    }
+
+    // Every subscription whose pattern matches the topic runs once, with the
+    // topic and the data.
+    fn receive_publish(&self, py: Python, body: &[u8]) {
+        let Ok(message) = Message::decode(body) else {
+            self.raise_error(
+                py,
+                ProtocolError::new_err("Malformed published message"),
+                None,
+            );
+            return;
+        };
+        let data = match message.data.to_py(py) {
+            Ok(data) => data,
+            Err(e) => {
+                self.raise_error(py, e, None);
+                return;
+            }
+        };
+        let events: Vec<Py<Event>> = self
+            .subscriptions
+            .borrow()
+            .iter()
+            .filter(|(pattern, _)| topics::matches(pattern, &message.event))
+            .map(|(_, event)| event.clone_ref(py))
+            .collect();
+        for event in events {
+            let args = PyTuple::new(py, [message.event.to_object(py), data.clone_ref(py)]);
+            self.call_handler(py, &event, args);
+        }
+    }
+
+    fn is_connected(&self, id: &str) -> bool {
+        transport::lock(&self.nodes).contains_key(id)
+    }
+
+    // Members the sending peer can reach are also reachable through it.
+    fn merge_members(&self, via: Option<&str>, members: Vec<members::Member>) {
+        {
+            let mut known = self.members.borrow_mut();
+            for member in members {
+                if member.id != self.config.id {
+                    known.merge_from(via, member);
+                }
+            }
+        }
+        self.dial_members();
+    }
+
+    fn dial_members(&self) {
+        let addresses = self
+            .members
+            .borrow_mut()
+            .dial_targets(&self.config.id, |id| self.is_connected(id));
+        for address in addresses {
+            self.dial(address, Origin::Introduced);
+        }
+    }
+
+    // Sends the member list, including our own peers, to every peer that
+    // exchanges member lists. Each list holds the distances to the members
+    // for the routing tables of the peers.
+    fn exchange_members(&self, py: Python) {
+        // The list of peers is not borrowed while dialing adds to it.
+        let peers: Vec<Py<Peer>> = self
+            .peers
+            .borrow()
+            .iter()
+            .map(|peer| peer.clone_ref(py))
+            .collect();
+        let peers: Vec<PyRef<Peer>> = peers
+            .iter()
+            .map(|peer| peer.borrow(py))
+            .filter(|peer| peer.connected.get())
+            .collect();
+
+        {
+            let mut members = self.members.borrow_mut();
+            for peer in &peers {
+                if let Some(id) = peer.node_id() {
+                    members.seen(&id, peer.listen_address());
+                }
+            }
+            members.expire(|id| self.is_connected(id));
+        }
+        let members = self.members.borrow();
+        for peer in peers
+            .iter()
+            .filter(|peer| peer.supports(handshake::MEMBERS))
+        {
+            let id = peer.node_id().unwrap_or_default();
+            let result = members
+                .encode_for(&id, |id| self.is_connected(id))
+                .map_err(io::Error::from)
+                .and_then(|body| Frame::encode(FrameType::Members, &body))
+                .and_then(|buffer| peer.write(&buffer));
+            // Failed writes show up as a read error on the other thread.
+            result.ok();
+        }
+        drop(members);
+        drop(peers);
+        self.dial_members();
+    }
+
+    // Pings every peer, and shuts down the connections to peers that went
+    // quiet, which ends their listen threads. Peers using the old format
+    // cannot answer pings.
+    fn heartbeat_loop(slf: &Py<Self>, heartbeat: Heartbeat, shutdown: &Shutdown) {
+        while !shutdown.wait(heartbeat.interval) {
+            Python::with_gil(|py| {
+                let slf = slf.borrow(py);
+                for peer in slf.peers.borrow().iter() {
+                    let peer = peer.borrow(py);
+                    let framing = peer.framing.get();
+                    if !peer.connected.get() || framing == Framing::Legacy {
+                        continue;
+                    }
+                    if peer.last_received.get().elapsed() > heartbeat.timeout {
+                        *peer.disconnect_reason.borrow_mut() = Some("timeout".to_string());
+                        peer.socket.borrow().shutdown().ok();
+                        continue;
+                    }
+                    let ping = match framing {
+                        Framing::WebSocket => Ok(websocket::encode(Opcode::Ping, &[])),
+                        _ => Frame::encode(FrameType::Ping, &[]),
+                    };
+                    // Failed writes show up as a read error on the other
+                    // thread.
+                    ping.and_then(|buffer| peer.write(&buffer)).ok();
+                }
+            });
+        }
+    }
+
+    // Exchanges member lists now and then, so changes spread through the
+    // whole mesh and members that left are forgotten.
+    fn members_loop(slf: &Py<Self>, shutdown: &Shutdown) {
+        while !shutdown.wait(members::INTERVAL) {
+            Python::with_gil(|py| slf.borrow(py).exchange_members(py));
+        }
+    }
+
+    // Asks every peer to connect to a node that just joined, except the
+    // node itself. Peers that exchange member lists learn about it that way
+    // instead.
+    fn request_connections(&self, py: Python, address: SocketAddr, except: Option<&Py<Peer>>) {
+        self.peers.borrow_mut().retain(|peer| {
+            if except.is_some_and(|except| except.is(peer)) {
+                return true;
+            }
+            let peer = peer.borrow(py);
+            if !peer.connected.get()
+                || peer.framing.get() == Framing::WebSocket
+                || peer.supports(handshake::MEMBERS)
+            {
+                return true;
+            }
+            // Older clients split the address on ':' themselves.
+            let data = if peer.framing.get() == Framing::Legacy {
+                Payload::Text(address.to_string())
+            } else {
+                Payload::Object(address::to_value(address))
+            };
+            let message = Message {
+                event: CONNECTION_REQUEST.to_string(),
+                data,
+            };
+            peer.encode(&message)
+                .map_or(true, |buffer| peer.write(&buffer).is_ok())
+        });
+    }
+
+    // The peer a datagram is from, found by the token it was given in the
+    // handshake.
+    fn datagram_peer(slf: &Py<Self>, token: u64) -> Option<Py<Peer>> {
+        Python::with_gil(|py| {
+            slf.borrow(py)
+                .peers
+                .borrow()
+                .iter()
+                .find(|peer| {
+                    let peer = peer.borrow(py);
+                    peer.token == token && peer.udp.borrow().is_some() && peer.connected.get()
+                })
+                .map(|peer| peer.clone_ref(py))
+        })
+    }
+
+    fn decode_datagram(slf: &Py<Self>, peer: &Py<Peer>, body: &[u8]) {
+        Python::with_gil(|py| {
+            let Ok(message) = Message::decode(body) else {
+                let error = ProtocolError::new_err("Malformed datagram");
+                slf.borrow(py).report(Some(peer.clone_ref(py)), error);
+                return;
+            };
+            peer.borrow(py).trigger(py, peer, message);
+        });
+    }
 }
@@ -0,0 +1,686 @@
+use socket2::{Domain, Protocol, Socket, Type};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::shutdown::Shutdown;
+
+// Networks are advertised as "<instance>._tknetwork._tcp.local" with a
+// TXT record holding their app and version.
+pub const SERVICE: &str = "_tknetwork._tcp.local";
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const PORT: u16 = 5353;
+const MAX_PACKET_LEN: usize = 9000;
+const TTL: u32 = 120;
+// How often the advertiser checks whether its network was closed.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+// Limits how many labels and compression pointers a name may take, so a
+// pointer loop cannot hang the parser.
+const MAX_LABELS: usize = 128;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+// The top bit of the class asks for a unicast response in questions and
+// flushes caches in answers.
+const CLASS_FLAG: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8400;
+
+type Name = Vec<String>;
+
+fn name(name: &str) -> Name {
+    name.split('.').map(str::to_string).collect()
+}
+
+fn same_name(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+struct Question {
+    name: Name,
+    kind: u16,
+    unicast: bool,
+}
+
+enum RecordData {
+    Ptr(Name),
+    Srv { port: u16, target: Name },
+    Txt(Vec<String>),
+    Address(IpAddr),
+    Other,
+}
+
+struct Record {
+    name: Name,
+    data: RecordData,
+}
+
+impl Record {
+    const fn kind(&self) -> u16 {
+        match &self.data {
+            RecordData::Ptr(_) => TYPE_PTR,
+            RecordData::Srv { .. } => TYPE_SRV,
+            RecordData::Txt(_) => TYPE_TXT,
+            RecordData::Address(IpAddr::V4(_)) => TYPE_A,
+            RecordData::Address(IpAddr::V6(_)) => TYPE_AAAA,
+            RecordData::Other => 0,
+        }
+    }
+
+    fn answers(&self, question: &Question) -> bool {
+        (question.kind == TYPE_ANY || question.kind == self.kind())
+            && same_name(&question.name, &self.name)
+    }
+}
+
+#[derive(Default)]
+struct Packet {
+    id: u16,
+    response: bool,
+    questions: Vec<Question>,
+    answers: Vec<Record>,
+    additional: Vec<Record>,
+}
+
+impl Packet {
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(512);
+        let flags = if self.response { FLAG_RESPONSE } else { 0 };
+        for field in [
+            self.id,
+            flags,
+            self.questions.len() as u16,
+            self.answers.len() as u16,
+            0,
+            self.additional.len() as u16,
+        ] {
+            buffer.extend_from_slice(&field.to_be_bytes());
+        }
+
+        for question in &self.questions {
+            encode_name(&mut buffer, &question.name);
+            buffer.extend_from_slice(&question.kind.to_be_bytes());
+            let class = if question.unicast {
+                CLASS_IN | CLASS_FLAG
+            } else {
+                CLASS_IN
+            };
+            buffer.extend_from_slice(&class.to_be_bytes());
+        }
+
+        for record in self.answers.iter().chain(&self.additional) {
+            encode_name(&mut buffer, &record.name);
+            buffer.extend_from_slice(&record.kind().to_be_bytes());
+            // Only the shared PTR record may have answers from several
+            // responders, the others belong to this network alone.
+            let class = match record.data {
+                RecordData::Ptr(_) => CLASS_IN,
+                _ => CLASS_IN | CLASS_FLAG,
+            };
+            buffer.extend_from_slice(&class.to_be_bytes());
+            buffer.extend_from_slice(&TTL.to_be_bytes());
+
+            let mut data = Vec::new();
+            match &record.data {
+                RecordData::Ptr(target) => encode_name(&mut data, target),
+                RecordData::Srv { port, target } => {
+                    data.extend_from_slice(&[0, 0, 0, 0]);
+                    data.extend_from_slice(&port.to_be_bytes());
+                    encode_name(&mut data, target);
+                }
+                RecordData::Txt(strings) => {
+                    for string in strings {
+                        let bytes = &string.as_bytes()[..string.len().min(255)];
+                        data.push(bytes.len() as u8);
+                        data.extend_from_slice(bytes);
+                    }
+                }
+                RecordData::Address(IpAddr::V4(ip)) => data.extend_from_slice(&ip.octets()),
+                RecordData::Address(IpAddr::V6(ip)) => data.extend_from_slice(&ip.octets()),
+                RecordData::Other => {}
+            }
+            buffer.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            buffer.extend_from_slice(&data);
+        }
+        buffer
+    }
+
+    // Authority records are read as additional records, neither is needed
+    // apart from the answers.
+    fn parse(buffer: &[u8]) -> Option<Self> {
+        let mut reader = Reader {
+            buffer,
+            position: 0,
+        };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+
+        let mut packet = Self {
+            id,
+            response: flags & 0x8000 != 0,
+            ..Self::default()
+        };
+        for _ in 0..counts[0] {
+            let name = reader.name()?;
+            let kind = reader.u16()?;
+            let class = reader.u16()?;
+            packet.questions.push(Question {
+                name,
+                kind,
+                unicast: class & CLASS_FLAG != 0,
+            });
+        }
+        for index in 0..counts[1] as usize + counts[2] as usize + counts[3] as usize {
+            let record = reader.record()?;
+            if index < counts[1] as usize {
+                packet.answers.push(record);
+            } else {
+                packet.additional.push(record);
+            }
+        }
+        Some(packet)
+    }
+}
+
+fn encode_name(buffer: &mut Vec<u8>, name: &[String]) {
+    for label in name.iter().filter(|label| !label.is_empty()) {
+        let bytes = &label.as_bytes()[..label.len().min(63)];
+        buffer.push(bytes.len() as u8);
+        buffer.extend_from_slice(bytes);
+    }
+    buffer.push(0);
+}
+
+struct Reader<'a> {
+    buffer: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, length: usize) -> Option<&[u8]> {
+        let bytes = self
+            .buffer
+            .get(self.position..self.position.checked_add(length)?)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    // Names may end in a pointer to a name earlier in the packet.
+    fn name(&mut self) -> Option<Name> {
+        let mut name = Vec::new();
+        let mut position = self.position;
+        let mut end = None;
+        for _ in 0..MAX_LABELS {
+            let length = *self.buffer.get(position)? as usize;
+            match length {
+                0 => {
+                    self.position = end.unwrap_or(position + 1);
+                    return Some(name);
+                }
+                0xC0.. => {
+                    let low = *self.buffer.get(position + 1)? as usize;
+                    end.get_or_insert(position + 2);
+                    position = (length & 0x3F) << 8 | low;
+                }
+                1..=63 => {
+                    let label = self.buffer.get(position + 1..position + 1 + length)?;
+                    name.push(String::from_utf8_lossy(label).into_owned());
+                    position += 1 + length;
+                }
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    fn record(&mut self) -> Option<Record> {
+        let name = self.name()?;
+        let kind = self.u16()?;
+        let _class = self.u16()?;
+        let _ttl = self.u32()?;
+        let length = self.u16()? as usize;
+        let start = self.position;
+        let end = start.checked_add(length)?;
+        if end > self.buffer.len() {
+            return None;
+        }
+
+        let data = match kind {
+            TYPE_PTR => RecordData::Ptr(self.name()?),
+            TYPE_SRV => {
+                self.bytes(4)?;
+                let port = self.u16()?;
+                RecordData::Srv {
+                    port,
+                    target: self.name()?,
+                }
+            }
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                while self.position < end {
+                    let length = *self.bytes(1)?.first()? as usize;
+                    strings.push(String::from_utf8_lossy(self.bytes(length)?).into_owned());
+                }
+                RecordData::Txt(strings)
+            }
+            TYPE_A => {
+                RecordData::Address(IpAddr::V4(<[u8; 4]>::try_from(self.bytes(4)?).ok()?.into()))
+            }
+            TYPE_AAAA => RecordData::Address(IpAddr::V6(Ipv6Addr::from(
+                <[u8; 16]>::try_from(self.bytes(16)?).ok()?,
+            ))),
+            _ => RecordData::Other,
+        };
+        self.position = end;
+        Some(Record { name, data })
+    }
+}
+
+// Answers queries for the service on the mDNS port.
+pub struct Advertiser {
+    socket: UdpSocket,
+    instance: Name,
+    host: Name,
+    port: u16,
+    txt: Vec<String>,
+    address: Option<Ipv4Addr>,
+}
+
+impl Advertiser {
+    // The interface is the IPv4 address of the network interface to
+    // advertise on, any interface when unspecified.
+    pub fn bind(interface: Ipv4Addr, label: &str, port: u16, txt: Vec<String>) -> io::Result<Self> {
+        let socket = multicast_socket(interface, PORT)?;
+        socket.join_multicast_v4(&GROUP, &interface)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let address = if interface.is_unspecified() {
+            outgoing_address()
+        } else {
+            Some(interface)
+        };
+        let mut instance = vec![label.to_string()];
+        instance.extend(name(SERVICE));
+        Ok(Self {
+            socket,
+            instance,
+            host: vec![host_label(label), "local".to_string()],
+            port,
+            txt,
+            address,
+        })
+    }
+
+    // Errors are passed to report, the advertiser keeps running.
+    pub fn run(&self, shutdown: &Shutdown, report: impl Fn(io::Error)) {
+        let announcement = Packet {
+            response: true,
+            answers: self.records(),
+            ..Packet::default()
+        };
+        if let Err(e) = self.socket.send_to(&announcement.encode(), (GROUP, PORT)) {
+            report(e);
+        }
+
+        let mut buffer = vec![0; MAX_PACKET_LEN];
+        while !shutdown.is_closed() {
+            let (bytes_read, address) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => {
+                    report(e);
+                    continue;
+                }
+            };
+            let Some(query) = Packet::parse(&buffer[..bytes_read]) else {
+                continue;
+            };
+            if query.response {
+                continue;
+            }
+            if let Some((response, unicast)) = self.answer(&query, address) {
+                let target = if unicast {
+                    address
+                } else {
+                    (GROUP, PORT).into()
+                };
+                if let Err(e) = self.socket.send_to(&response.encode(), target) {
+                    report(e);
+                }
+            }
+        }
+    }
+
+    fn records(&self) -> Vec<Record> {
+        let mut records = vec![
+            Record {
+                name: name(SERVICE),
+                data: RecordData::Ptr(self.instance.clone()),
+            },
+            Record {
+                name: self.instance.clone(),
+                data: RecordData::Srv {
+                    port: self.port,
+                    target: self.host.clone(),
+                },
+            },
+            Record {
+                name: self.instance.clone(),
+                data: RecordData::Txt(self.txt.clone()),
+            },
+        ];
+        if let Some(address) = self.address {
+            records.push(Record {
+                name: self.host.clone(),
+                data: RecordData::Address(IpAddr::V4(address)),
+            });
+        }
+        records
+    }
+
+    // Queries sent from another port than 5353 come from simple resolvers
+    // that only listen for a unicast reply to the same query id.
+    fn answer(&self, query: &Packet, from: SocketAddr) -> Option<(Packet, bool)> {
+        let (answers, additional): (Vec<_>, Vec<_>) =
+            self.records().into_iter().partition(|record| {
+                query
+                    .questions
+                    .iter()
+                    .any(|question| record.answers(question))
+            });
+        if answers.is_empty() {
+            return None;
+        }
+
+        let legacy = from.port() != PORT;
+        let response = Packet {
+            id: if legacy { query.id } else { 0 },
+            response: true,
+            questions: Vec::new(),
+            answers,
+            additional,
+        };
+        let unicast = legacy || query.questions.iter().any(|question| question.unicast);
+        Some((response, unicast))
+    }
+}
+
+// Sends multicast on the interface and loops it back, so networks on the
+// same host see each other. Every responder on the host shares port 5353.
+fn multicast_socket(interface: Ipv4Addr, port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    Ok(socket.into())
+}
+
+// Host names are limited to letters, digits and hyphens.
+fn host_label(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+// The address the host would send multicast from, found by connecting a
+// UDP socket, which sends nothing.
+fn outgoing_address() -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((GROUP, PORT)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+pub struct Service {
+    pub name: String,
+    pub address: SocketAddr,
+    pub txt: HashMap<String, String>,
+}
+
+// Asks for the service and collects the answers until the timeout.
+pub fn browse(interface: Ipv4Addr, timeout: Duration) -> io::Result<Vec<Service>> {
+    let socket = multicast_socket(interface, 0)?;
+    let query = Packet {
+        questions: vec![Question {
+            name: name(SERVICE),
+            kind: TYPE_PTR,
+            unicast: true,
+        }],
+        ..Packet::default()
+    };
+    socket.send_to(&query.encode(), (GROUP, PORT))?;
+
+    let mut instances: Vec<Name> = Vec::new();
+    let mut sources = HashMap::new();
+    let mut servers = HashMap::new();
+    let mut texts = HashMap::new();
+    let mut addresses = HashMap::new();
+    let deadline = Instant::now() + timeout;
+    let mut buffer = vec![0; MAX_PACKET_LEN];
+    while let Some(remaining) = deadline
+        .checked_duration_since(Instant::now())
+        .filter(|remaining| !remaining.is_zero())
+    {
+        socket.set_read_timeout(Some(remaining))?;
+        let (bytes_read, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        };
+        let Some(packet) = Packet::parse(&buffer[..bytes_read]) else {
+            continue;
+        };
+        if !packet.response {
+            continue;
+        }
+
+        for record in packet.answers.into_iter().chain(packet.additional) {
+            let key = record.name.join(".").to_ascii_lowercase();
+            match record.data {
+                RecordData::Ptr(instance)
+                    if same_name(&record.name, &name(SERVICE))
+                        && !instances.iter().any(|known| same_name(known, &instance)) =>
+                {
+                    sources.insert(instance.join(".").to_ascii_lowercase(), from.ip());
+                    instances.push(instance);
+                }
+                RecordData::Srv { port, target } => {
+                    servers.insert(key, (port, target.join(".").to_ascii_lowercase()));
+                }
+                RecordData::Txt(strings) => {
+                    texts.insert(key, strings);
+                }
+                RecordData::Address(ip) => {
+                    addresses.entry(key).or_insert(ip);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(instances
+        .into_iter()
+        .filter_map(|instance| {
+            let key = instance.join(".").to_ascii_lowercase();
+            let (port, target) = servers.get(&key)?;
+            // Responders that leave out the address record are reached at
+            // the address they answered from.
+            let ip = addresses.get(target).or_else(|| sources.get(&key))?;
+            let txt = texts
+                .get(&key)
+                .into_iter()
+                .flatten()
+                .filter_map(|string| {
+                    let (key, value) = string.split_once('=')?;
+                    Some((key.to_string(), value.to_string()))
+                })
+                .collect();
+            Some(Service {
+                name: instance.first()?.clone(),
+                address: SocketAddr::new(*ip, *port),
+                txt,
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(buffer: &[u8]) -> Reader<'_> {
+        Reader {
+            buffer,
+            position: 0,
+        }
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let instance = name("chat-1._tknetwork._tcp.local");
+        let packet = Packet {
+            id: 7,
+            response: true,
+            questions: vec![Question {
+                name: name(SERVICE),
+                kind: TYPE_PTR,
+                unicast: true,
+            }],
+            answers: vec![Record {
+                name: name(SERVICE),
+                data: RecordData::Ptr(instance.clone()),
+            }],
+            additional: vec![
+                Record {
+                    name: instance.clone(),
+                    data: RecordData::Srv {
+                        port: 6000,
+                        target: name("chat-1.local"),
+                    },
+                },
+                Record {
+                    name: instance.clone(),
+                    data: RecordData::Txt(vec!["app=chat".to_string(), String::new()]),
+                },
+                Record {
+                    name: name("chat-1.local"),
+                    data: RecordData::Address(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+                },
+                Record {
+                    name: name("chat-1.local"),
+                    data: RecordData::Address(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+                },
+            ],
+        };
+        let parsed = Packet::parse(&packet.encode()).unwrap();
+
+        assert_eq!((parsed.id, parsed.response), (7, true));
+        assert_eq!(parsed.questions.len(), 1);
+        assert!(parsed.questions[0].unicast);
+        assert!(parsed.answers[0].answers(&parsed.questions[0]));
+        assert!(matches!(&parsed.answers[0].data, RecordData::Ptr(name) if *name == instance));
+        assert!(matches!(
+            &parsed.additional[0].data,
+            RecordData::Srv { port: 6000, target } if *target == name("chat-1.local")
+        ));
+        assert!(matches!(
+            &parsed.additional[1].data,
+            RecordData::Txt(strings) if *strings == ["app=chat", ""]
+        ));
+        assert_eq!(parsed.additional[2].kind(), TYPE_A);
+        assert!(matches!(
+            parsed.additional[3].data,
+            RecordData::Address(IpAddr::V6(ip)) if ip == Ipv6Addr::LOCALHOST
+        ));
+    }
+
+    #[test]
+    fn compressed_names() {
+        // "local" at 0, then "a" followed by a pointer to it.
+        let buffer = [5, b'l', b'o', b'c', b'a', b'l', 0, 1, b'a', 0xC0, 0, 0xff];
+        let mut reader = reader(&buffer);
+        reader.position = 7;
+        assert_eq!(reader.name().unwrap(), ["a", "local"]);
+        assert_eq!(reader.position, 11);
+    }
+
+    #[test]
+    fn malformed_names() {
+        assert!(reader(&[0xC0, 0]).name().is_none());
+        assert!(reader(&[1, b'a', 0xC0, 0]).name().is_none());
+        assert!(reader(&[0xC0]).name().is_none());
+        assert!(reader(&[0xC0, 9]).name().is_none());
+        assert!(reader(&[3, b'a', b'b']).name().is_none());
+        assert!(reader(&[64]).name().is_none());
+        assert!(reader(&[]).name().is_none());
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let packet = Packet {
+            questions: vec![Question {
+                name: name(SERVICE),
+                kind: TYPE_PTR,
+                unicast: false,
+            }],
+            answers: vec![Record {
+                name: name(SERVICE),
+                data: RecordData::Ptr(name("a.local")),
+            }],
+            ..Packet::default()
+        };
+        let buffer = packet.encode();
+        for length in 0..buffer.len() {
+            assert!(Packet::parse(&buffer[..length]).is_none());
+        }
+
+        // A record whose data is longer than the packet.
+        let mut too_long = buffer.clone();
+        let length = too_long.len() - 2 - "a.local".len() - 2;
+        too_long[length..length + 2].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert!(Packet::parse(&too_long).is_none());
+
+        // Unknown record types are skipped over.
+        let mut unknown = buffer;
+        let kind = unknown.len() - 10 - "a.local".len() - 2;
+        unknown[kind..kind + 2].copy_from_slice(&99u16.to_be_bytes());
+        let parsed = Packet::parse(&unknown).unwrap();
+        assert!(matches!(parsed.answers[0].data, RecordData::Other));
+    }
+}
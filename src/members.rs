@@ -0,0 +1,371 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const INTERVAL: Duration = Duration::from_secs(5);
+// Members that nobody has seen for this many seconds are forgotten.
+const EXPIRY: f64 = 60.0;
+// How long to wait before dialing a member again after a failed attempt.
+const REDIAL: Duration = Duration::from_secs(30);
+const MAX_ADDRESSES: usize = 4;
+// Routes that were not announced again for this long are dropped.
+const ROUTE_EXPIRY: Duration = Duration::from_secs(15);
+// Longer routes are ignored, which also ends routes that keep growing
+// through loops after the member is gone.
+pub const MAX_HOPS: u32 = 16;
+// Peers can send lists of any length, so only this many members are taken
+// from each list and kept at once.
+const MAX_MEMBERS: usize = 1024;
+
+// A network known to be part of the mesh, as exchanged between peers.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Member {
+    pub id: String,
+    pub addresses: Vec<SocketAddr>,
+    // Seconds since the Unix epoch when a peer of the member last saw it.
+    pub last_seen: f64,
+    // How many hops the sender of the list is away from the member, when
+    // it can reach it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hops: Option<u32>,
+}
+
+struct Route {
+    hops: u32,
+    updated: Instant,
+}
+
+struct Entry {
+    member: Member,
+    dialed: Option<Instant>,
+    // Routes to the member by the node id of the peer to send through.
+    routes: HashMap<String, Route>,
+}
+
+impl Entry {
+    fn best_route(&self, connected: impl Fn(&str) -> bool) -> Option<(&str, u32)> {
+        self.routes
+            .iter()
+            .filter(|(via, route)| connected(via) && route.updated.elapsed() < ROUTE_EXPIRY)
+            .map(|(via, route)| (via.as_str(), route.hops))
+            .min_by_key(|&(_, hops)| hops)
+    }
+}
+
+#[derive(Default)]
+pub struct Members {
+    entries: HashMap<String, Entry>,
+}
+
+pub fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64())
+}
+
+impl Members {
+    // Records a member that is connected to this network.
+    pub fn seen(&mut self, id: &str, address: Option<SocketAddr>) {
+        self.merge(Member {
+            id: id.to_string(),
+            addresses: address.into_iter().collect(),
+            last_seen: now(),
+            hops: None,
+        });
+    }
+
+    // Merges a member from the list sent by a peer, which can route to it
+    // when it is close enough. Members with ids no network has, or that
+    // do not fit in the table, are left out.
+    pub fn merge_from(&mut self, via: Option<&str>, mut member: Member) {
+        let full = self.entries.len() >= MAX_MEMBERS && !self.entries.contains_key(&member.id);
+        if !valid_id(&member.id) || !member.last_seen.is_finite() || full {
+            return;
+        }
+        // Clocks of peers may be ahead, and a time in the future would keep
+        // the member from ever expiring.
+        member.last_seen = member.last_seen.min(now());
+        let id = member.id.clone();
+        let hops = match member.hops.take().map(|hops| hops.checked_add(1)) {
+            // No peer sends such hop counts, so the member is left out.
+            Some(None) => return,
+            hops => hops.flatten(),
+        };
+        self.merge(member);
+        if let (Some(via), Some(hops), Some(entry)) = (via, hops, self.entries.get_mut(&id)) {
+            if hops <= MAX_HOPS {
+                let updated = Instant::now();
+                entry
+                    .routes
+                    .insert(via.to_string(), Route { hops, updated });
+            }
+        }
+    }
+
+    fn merge(&mut self, member: Member) {
+        let Some(entry) = self.entries.get_mut(&member.id) else {
+            self.entries.insert(
+                member.id.clone(),
+                Entry {
+                    member,
+                    dialed: None,
+                    routes: HashMap::new(),
+                },
+            );
+            return;
+        };
+
+        let known = &mut entry.member;
+        known.last_seen = known.last_seen.max(member.last_seen);
+        // Newer addresses go first, as they are more likely to work.
+        let mut addresses = member.addresses;
+        for address in known.addresses.drain(..) {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+        addresses.truncate(MAX_ADDRESSES);
+        known.addresses = addresses;
+    }
+
+    pub fn expire(&mut self, connected: impl Fn(&str) -> bool) {
+        let now = now();
+        self.entries
+            .retain(|id, entry| connected(id) || now - entry.member.last_seen < EXPIRY);
+        for entry in self.entries.values_mut() {
+            entry
+                .routes
+                .retain(|_, route| route.updated.elapsed() < ROUTE_EXPIRY);
+        }
+    }
+
+    // The peer to send a message for the member through, along the
+    // shortest known route.
+    pub fn next_hop(&self, id: &str, connected: impl Fn(&str) -> bool) -> Option<String> {
+        let (via, _) = self.entries.get(id)?.best_route(connected)?;
+        Some(via.to_string())
+    }
+
+    // Returns an address of every member to connect to. Of two members only
+    // the one with the lower id dials, and members are not dialed again
+    // until a while after the last attempt.
+    pub fn dial_targets(
+        &mut self,
+        own_id: &str,
+        connected: impl Fn(&str) -> bool,
+    ) -> Vec<SocketAddr> {
+        self.entries
+            .iter_mut()
+            .filter(|(id, entry)| {
+                own_id < id.as_str()
+                    && !connected(id)
+                    && entry.dialed.is_none_or(|dialed| dialed.elapsed() >= REDIAL)
+            })
+            .filter_map(|(_, entry)| {
+                let address = *entry.member.addresses.first()?;
+                entry.dialed = Some(Instant::now());
+                Some(address)
+            })
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Member> {
+        self.entries.values().map(|entry| &entry.member)
+    }
+
+    // Routes through the peer the list is for are left out, so it never
+    // routes back through us to reach them.
+    pub fn encode_for(
+        &self,
+        peer: &str,
+        connected: impl Fn(&str) -> bool,
+    ) -> serde_json::Result<Vec<u8>> {
+        let members: Vec<Member> = self
+            .entries
+            .iter()
+            .filter(|(id, _)| id.as_str() != peer)
+            .map(|(id, entry)| {
+                let hops = if connected(id) {
+                    Some(1)
+                } else {
+                    entry
+                        .best_route(|via| via != peer && connected(via))
+                        .map(|(_, hops)| hops)
+                };
+                Member {
+                    hops,
+                    ..entry.member.clone()
+                }
+            })
+            .collect();
+        serde_json::to_vec(&members)
+    }
+
+    pub fn decode(body: &[u8]) -> Option<Vec<Member>> {
+        let mut members: Vec<Member> = serde_json::from_slice(body).ok()?;
+        members.truncate(MAX_MEMBERS);
+        Some(members)
+    }
+}
+
+// Routed messages carry node ids behind a length of one byte, so longer
+// ids could not be reached anyway.
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= u8::MAX as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(id: &str, addresses: &[&str], last_seen: f64) -> Member {
+        Member {
+            id: id.to_string(),
+            addresses: addresses.iter().map(|a| a.parse().unwrap()).collect(),
+            last_seen,
+            hops: None,
+        }
+    }
+
+    fn addresses(members: &Members, id: &str) -> Vec<String> {
+        let member = members.iter().find(|member| member.id == id).unwrap();
+        member.addresses.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn merging_members() {
+        let mut members = Members::default();
+        members.merge_from(None, member("b", &["10.0.0.1:1", "10.0.0.2:1"], 10.0));
+        members.merge_from(None, member("b", &["10.0.0.2:1", "10.0.0.3:1"], 5.0));
+        assert_eq!(
+            addresses(&members, "b"),
+            ["10.0.0.2:1", "10.0.0.3:1", "10.0.0.1:1"]
+        );
+        assert_eq!(members.iter().next().unwrap().last_seen, 10.0);
+
+        let many = ["10.0.1.1:1", "10.0.1.2:1", "10.0.1.3:1", "10.0.1.4:1"];
+        members.merge_from(None, member("b", &many, 20.0));
+        assert_eq!(addresses(&members, "b"), many);
+        assert_eq!(members.iter().next().unwrap().last_seen, 20.0);
+    }
+
+    #[test]
+    fn lower_ids_dial_once() {
+        let mut members = Members::default();
+        members.merge_from(None, member("a", &["10.0.0.1:1"], now()));
+        members.merge_from(None, member("c", &["10.0.0.3:1"], now()));
+        members.merge_from(None, member("d", &["10.0.0.4:1"], now()));
+        members.merge_from(None, member("e", &[], now()));
+
+        let targets = members.dial_targets("b", |id| id == "d");
+        assert_eq!(targets, ["10.0.0.3:1".parse::<SocketAddr>().unwrap()]);
+        assert!(members
+            .dial_targets("b", |_| false)
+            .contains(&"10.0.0.4:1".parse().unwrap()));
+        assert!(members.dial_targets("b", |_| false).is_empty());
+    }
+
+    #[test]
+    fn stale_members_expire() {
+        let mut members = Members::default();
+        members.merge_from(None, member("old", &[], now() - EXPIRY - 1.0));
+        members.merge_from(None, member("connected", &[], now() - EXPIRY - 1.0));
+        members.seen("recent", None);
+        members.expire(|id| id == "connected");
+
+        let mut ids: Vec<&str> = members.iter().map(|member| member.id.as_str()).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, ["connected", "recent"]);
+    }
+
+    #[test]
+    fn untrusted_members() {
+        let mut members = Members::default();
+        members.merge_from(None, member("future", &[], now() + 1e9));
+        members.merge_from(None, member("", &[], now()));
+        members.merge_from(None, member(&"a".repeat(256), &[], now()));
+        members.merge_from(None, member("never", &[], f64::NAN));
+        assert_eq!(members.iter().count(), 1);
+        assert!(members.iter().next().unwrap().last_seen <= now());
+
+        for i in 0..MAX_MEMBERS {
+            members.merge_from(None, member(&format!("m{i}"), &[], now()));
+        }
+        assert_eq!(members.iter().count(), MAX_MEMBERS);
+        members.merge_from(None, member("future", &["10.0.0.1:1"], now()));
+        assert_eq!(addresses(&members, "future"), ["10.0.0.1:1"]);
+        members.seen("connected", None);
+        assert_eq!(members.iter().count(), MAX_MEMBERS + 1);
+
+        let list: Vec<Member> = (0..MAX_MEMBERS + 1)
+            .map(|i| member(&format!("m{i}"), &[], now()))
+            .collect();
+        let decoded = Members::decode(&serde_json::to_vec(&list).unwrap()).unwrap();
+        assert_eq!(decoded.len(), MAX_MEMBERS);
+    }
+
+    #[test]
+    fn member_lists() {
+        let mut members = Members::default();
+        members.seen("b", Some("10.0.0.2:1".parse().unwrap()));
+        members.seen("c", None);
+
+        let body = members.encode_for("c", |_| true).unwrap();
+        let decoded = Members::decode(&body).unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].id, "b");
+        assert_eq!(decoded[0].hops, Some(1));
+        assert_eq!(decoded[0].addresses, ["10.0.0.2:1".parse().unwrap()]);
+
+        assert!(Members::decode(b"{}").is_none());
+        assert!(Members::decode(br#"[{"id": "a"}]"#).is_none());
+        assert!(Members::decode(br#"[{"id": "a", "addresses": ["x"], "last_seen": 1}]"#).is_none());
+    }
+
+    fn routed(hops: u32) -> Member {
+        Member {
+            hops: Some(hops),
+            ..member("far", &[], now())
+        }
+    }
+
+    #[test]
+    fn shortest_routes() {
+        let mut members = Members::default();
+        members.merge_from(Some("b"), routed(2));
+        members.merge_from(Some("c"), routed(1));
+        members.merge_from(Some("d"), routed(MAX_HOPS));
+        assert_eq!(members.next_hop("far", |_| true).as_deref(), Some("c"));
+        assert_eq!(
+            members.next_hop("far", |via| via != "c").as_deref(),
+            Some("b")
+        );
+        assert_eq!(members.next_hop("far", |via| via == "d"), None);
+        assert_eq!(members.next_hop("unknown", |_| true), None);
+
+        members.merge_from(
+            Some("b"),
+            Member {
+                id: "overflow".to_string(),
+                ..routed(u32::MAX)
+            },
+        );
+        assert!(members.iter().all(|member| member.id != "overflow"));
+    }
+
+    #[test]
+    fn routes_are_not_sent_back() {
+        let mut members = Members::default();
+        members.seen("c", None);
+        members.merge_from(Some("c"), routed(1));
+        let connected = |id: &str| id == "c";
+
+        let decoded = Members::decode(&members.encode_for("c", connected).unwrap()).unwrap();
+        assert_eq!(decoded[0].hops, None);
+        let decoded = Members::decode(&members.encode_for("e", connected).unwrap()).unwrap();
+        let far = decoded.iter().find(|member| member.id == "far").unwrap();
+        assert_eq!(far.hops, Some(2));
+    }
+}
@@ -0,0 +1,257 @@
+use pyo3::buffer::PyBuffer;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyString};
+
+use crate::codec::{Codec, Value};
+use serde::{Deserialize, Serialize};
+
+use std::io;
+
+// Message frame body:
+//   event length (2, big endian) | event | payload kind (1) | payload
+const TEXT: u8 = 0;
+const BINARY: u8 = 1;
+const JSON: u8 = 2;
+const MESSAGE_PACK: u8 = 3;
+const CBOR: u8 = 4;
+
+#[derive(Clone, Debug)]
+pub enum Payload {
+    Text(String),
+    Binary(Vec<u8>),
+    Object(Value),
+}
+
+impl Payload {
+    pub fn to_py(&self, py: Python) -> PyResult<PyObject> {
+        match self {
+            Self::Text(text) => Ok(text.to_object(py)),
+            Self::Binary(bytes) => Ok(PyBytes::new(py, bytes).into()),
+            Self::Object(value) => value.to_py(py),
+        }
+    }
+}
+
+impl<'source> FromPyObject<'source> for Payload {
+    fn extract(obj: &'source PyAny) -> PyResult<Self> {
+        if let Ok(text) = obj.downcast::<PyString>() {
+            return Ok(Self::Text(text.to_str()?.to_string()));
+        }
+        if let Ok(bytes) = obj.downcast::<PyBytes>() {
+            return Ok(Self::Binary(bytes.as_bytes().to_vec()));
+        }
+        if let Ok(buffer) = PyBuffer::<u8>::get(obj) {
+            return buffer.to_vec(obj.py()).map(Self::Binary);
+        }
+        Value::extract(obj).map(Self::Object)
+    }
+}
+
+#[derive(Debug)]
+pub struct Message {
+    pub event: String,
+    pub data: Payload,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyMessage {
+    event: String,
+    data: String,
+}
+
+// Sent to WebSocket clients, which read it with JSON.parse.
+#[derive(Serialize)]
+struct JsonMessage<'a> {
+    event: &'a str,
+    data: &'a Value,
+}
+
+#[derive(Deserialize)]
+struct OwnedJsonMessage {
+    event: String,
+    #[serde(default)]
+    data: Option<Value>,
+}
+
+impl Message {
+    pub fn encode(&self, codec: Codec) -> io::Result<Vec<u8>> {
+        let event = self.event.as_bytes();
+        let length =
+            u16::try_from(event.len()).map_err(|_| invalid_data("Event name is too long"))?;
+        let encoded;
+        let (kind, data) = match &self.data {
+            Payload::Text(text) => (TEXT, text.as_bytes()),
+            Payload::Binary(bytes) => (BINARY, bytes.as_slice()),
+            Payload::Object(value) => {
+                encoded = codec.encode(value)?;
+                (codec_kind(codec), encoded.as_slice())
+            }
+        };
+
+        let mut buffer = Vec::with_capacity(3 + event.len() + data.len());
+        buffer.extend_from_slice(&length.to_be_bytes());
+        buffer.extend_from_slice(event);
+        buffer.push(kind);
+        buffer.extend_from_slice(data);
+        Ok(buffer)
+    }
+
+    pub fn decode(buffer: &[u8]) -> io::Result<Self> {
+        let malformed = || invalid_data("Malformed message");
+
+        let (length, rest) = buffer.split_at_checked(2).ok_or_else(malformed)?;
+        let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+        let (event, rest) = rest.split_at_checked(length).ok_or_else(malformed)?;
+        let event = std::str::from_utf8(event)
+            .map_err(|_| malformed())?
+            .to_string();
+        let (&kind, data) = rest.split_first().ok_or_else(malformed)?;
+
+        let data = match kind {
+            TEXT => Payload::Text(String::from_utf8(data.to_vec()).map_err(|_| malformed())?),
+            BINARY => Payload::Binary(data.to_vec()),
+            JSON => Payload::Object(Codec::Json.decode(data)?),
+            MESSAGE_PACK => Payload::Object(Codec::MessagePack.decode(data)?),
+            CBOR => Payload::Object(Codec::Cbor.decode(data)?),
+            _ => return Err(malformed()),
+        };
+        Ok(Self { event, data })
+    }
+
+    // Older clients only know strings, so objects are sent to them as JSON text.
+    pub fn encode_legacy(&self) -> io::Result<Vec<u8>> {
+        let data = match &self.data {
+            Payload::Text(text) => text.clone(),
+            Payload::Object(value) => serde_json::to_string(value)?,
+            Payload::Binary(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Legacy peers do not accept binary payloads",
+                ))
+            }
+        };
+        Ok(serde_json::to_vec(&LegacyMessage {
+            event: self.event.clone(),
+            data,
+        })?)
+    }
+
+    pub fn encode_json(&self) -> io::Result<Vec<u8>> {
+        let text;
+        let data = match &self.data {
+            Payload::Text(data) => {
+                text = Value::Text(data.clone());
+                &text
+            }
+            Payload::Object(value) => value,
+            Payload::Binary(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Binary payloads cannot be sent as JSON",
+                ))
+            }
+        };
+        Ok(serde_json::to_vec(&JsonMessage {
+            event: &self.event,
+            data,
+        })?)
+    }
+
+    pub fn decode_json(buffer: &[u8]) -> io::Result<Self> {
+        let message: OwnedJsonMessage = serde_json::from_slice(buffer)?;
+        let data = match message.data.unwrap_or(Value::None) {
+            Value::Text(text) => Payload::Text(text),
+            value => Payload::Object(value),
+        };
+        Ok(Self {
+            event: message.event,
+            data,
+        })
+    }
+
+    pub fn decode_legacy(buffer: &[u8]) -> io::Result<Self> {
+        let message: LegacyMessage = serde_json::from_slice(buffer)?;
+        Ok(Self {
+            event: message.event,
+            data: Payload::Text(message.data),
+        })
+    }
+}
+
+const fn codec_kind(codec: Codec) -> u8 {
+    match codec {
+        Codec::Json => JSON,
+        Codec::MessagePack => MESSAGE_PACK,
+        Codec::Cbor => CBOR,
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: Payload, codec: Codec) -> Payload {
+        let message = Message {
+            event: "draw".to_string(),
+            data,
+        };
+        let decoded = Message::decode(&message.encode(codec).unwrap()).unwrap();
+        assert_eq!(decoded.event, "draw");
+        decoded.data
+    }
+
+    #[test]
+    fn payloads_round_trip() {
+        assert!(matches!(
+            round_trip(Payload::Text("é".to_string()), Codec::Json),
+            Payload::Text(text) if text == "é"
+        ));
+        assert!(matches!(
+            round_trip(Payload::Binary(vec![0, 4, 255]), Codec::Json),
+            Payload::Binary(bytes) if bytes == [0, 4, 255]
+        ));
+        let value = Value::List(vec![Value::Int(1), Value::Text("a".to_string())]);
+        for codec in [Codec::Json, Codec::MessagePack, Codec::Cbor] {
+            assert!(matches!(
+                round_trip(Payload::Object(value.clone()), codec),
+                Payload::Object(decoded) if decoded == value
+            ));
+        }
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let message = Message {
+            event: "draw".to_string(),
+            data: Payload::Text("line".to_string()),
+        };
+        let buffer = message.encode(Codec::Json).unwrap();
+
+        assert!(Message::decode(&[]).is_err());
+        assert!(Message::decode(&buffer[..1]).is_err());
+        assert!(Message::decode(&buffer[..2 + 3]).is_err());
+        assert!(Message::decode(&buffer[..2 + 4]).is_err());
+
+        let mut unknown_kind = buffer.clone();
+        unknown_kind[2 + 4] = 0xff;
+        assert!(Message::decode(&unknown_kind).is_err());
+
+        let mut invalid_text = buffer.clone();
+        invalid_text.push(0xff);
+        assert!(Message::decode(&invalid_text).is_err());
+
+        let mut invalid_event = buffer;
+        invalid_event[2] = 0xff;
+        assert!(Message::decode(&invalid_event).is_err());
+
+        let long = Message {
+            event: "a".repeat(u16::MAX as usize + 1),
+            data: Payload::Binary(Vec::new()),
+        };
+        assert!(long.encode(Codec::Json).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashSet;
+
+// A message for the peers that joined a room:
+//   room length (1) | room name | encoded message
+pub struct RoomMessage<'a> {
+    pub room: &'a str,
+    pub message: &'a [u8],
+}
+
+impl<'a> RoomMessage<'a> {
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        let room = u8::try_from(self.room.len()).ok()?;
+        let mut buffer = Vec::with_capacity(1 + self.room.len() + self.message.len());
+        buffer.push(room);
+        buffer.extend_from_slice(self.room.as_bytes());
+        buffer.extend_from_slice(self.message);
+        Some(buffer)
+    }
+
+    pub fn parse(buffer: &'a [u8]) -> Option<Self> {
+        let (length, rest) = buffer.split_first()?;
+        let (room, message) = rest.split_at_checked(*length as usize)?;
+        Some(Self {
+            room: std::str::from_utf8(room).ok()?,
+            message,
+        })
+    }
+}
+
+// Room names travel with every room message, so they must fit its header.
+pub fn valid(room: &str) -> bool {
+    !room.is_empty() && room.len() <= u8::MAX as usize
+}
+
+// Peers send the full list of rooms they joined whenever it changes.
+pub fn encode(rooms: &HashSet<String>) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(rooms)
+}
+
+pub fn decode(body: &[u8]) -> Option<HashSet<String>> {
+    serde_json::from_slice(body).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_messages_round_trip() {
+        let message = RoomMessage {
+            room: "lobby",
+            message: b"message",
+        };
+        let buffer = message.encode().unwrap();
+        let parsed = RoomMessage::parse(&buffer).unwrap();
+        assert_eq!((parsed.room, parsed.message), ("lobby", &b"message"[..]));
+    }
+
+    #[test]
+    fn malformed_room_messages() {
+        let long = "a".repeat(u8::MAX as usize + 1);
+        let message = RoomMessage {
+            room: &long,
+            message: b"",
+        };
+        assert!(message.encode().is_none());
+
+        assert!(RoomMessage::parse(&[]).is_none());
+        assert!(RoomMessage::parse(&[5, b'a']).is_none());
+        assert!(RoomMessage::parse(&[1, 0xff]).is_none());
+    }
+
+    #[test]
+    fn room_names_and_lists() {
+        assert!(valid("lobby"));
+        assert!(!valid(""));
+        assert!(valid(&"a".repeat(u8::MAX as usize)));
+        assert!(!valid(&"a".repeat(u8::MAX as usize + 1)));
+
+        let rooms: HashSet<String> = ["a".to_string(), "b".to_string()].into();
+        assert_eq!(decode(&encode(&rooms).unwrap()), Some(rooms));
+        assert_eq!(decode(b"{}"), None);
+        assert_eq!(decode(b"[1]"), None);
+    }
+}
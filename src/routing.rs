@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+
+// Enough for the longest routes members::MAX_HOPS allows.
+pub const DEFAULT_TTL: u8 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Message = 0,
+    // Sent back to the origin of a message that could not be delivered.
+    Undeliverable = 1,
+}
+
+// A message for a single node, relayed along the routes in the member
+// lists:
+//   kind (1) | hops left (1) | destination length (1) | destination node id
+//   | origin length (1) | origin node id | body
+// The body is the encoded message, or a JSON Undeliverable.
+pub struct Routed<'a> {
+    pub kind: Kind,
+    pub ttl: u8,
+    pub destination: &'a str,
+    pub origin: &'a str,
+    pub body: &'a [u8],
+}
+
+impl<'a> Routed<'a> {
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        let destination = u8::try_from(self.destination.len()).ok()?;
+        let origin = u8::try_from(self.origin.len()).ok()?;
+        let mut buffer =
+            Vec::with_capacity(4 + self.destination.len() + self.origin.len() + self.body.len());
+        buffer.push(self.kind as u8);
+        buffer.push(self.ttl);
+        buffer.push(destination);
+        buffer.extend_from_slice(self.destination.as_bytes());
+        buffer.push(origin);
+        buffer.extend_from_slice(self.origin.as_bytes());
+        buffer.extend_from_slice(self.body);
+        Some(buffer)
+    }
+
+    pub fn parse(buffer: &'a [u8]) -> Option<Self> {
+        let (header, rest) = buffer.split_at_checked(3)?;
+        let kind = match header[0] {
+            0 => Kind::Message,
+            1 => Kind::Undeliverable,
+            _ => return None,
+        };
+        let (destination, rest) = rest.split_at_checked(header[2] as usize)?;
+        let (length, rest) = rest.split_first()?;
+        let (origin, body) = rest.split_at_checked(*length as usize)?;
+        Some(Self {
+            kind,
+            ttl: header[1],
+            destination: std::str::from_utf8(destination).ok()?,
+            origin: std::str::from_utf8(origin).ok()?,
+            body,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Undeliverable {
+    pub node_id: String,
+    pub event: String,
+    pub reason: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routed_round_trip() {
+        let routed = Routed {
+            kind: Kind::Undeliverable,
+            ttl: DEFAULT_TTL,
+            destination: "destination",
+            origin: "origin",
+            body: b"body",
+        };
+        let buffer = routed.encode().unwrap();
+        let parsed = Routed::parse(&buffer).unwrap();
+        assert_eq!(
+            (parsed.kind, parsed.ttl),
+            (Kind::Undeliverable, DEFAULT_TTL)
+        );
+        assert_eq!(
+            (parsed.destination, parsed.origin),
+            ("destination", "origin")
+        );
+        assert_eq!(parsed.body, b"body");
+    }
+
+    #[test]
+    fn malformed_routed() {
+        let long = "a".repeat(u8::MAX as usize + 1);
+        let routed = Routed {
+            kind: Kind::Message,
+            ttl: 1,
+            destination: &long,
+            origin: "b",
+            body: b"",
+        };
+        assert!(routed.encode().is_none());
+        assert!(Routed {
+            destination: "a",
+            origin: &long,
+            ..routed
+        }
+        .encode()
+        .is_none());
+
+        let buffer = Routed {
+            destination: "a",
+            origin: "b",
+            ..routed
+        }
+        .encode()
+        .unwrap();
+        for length in 0..buffer.len() {
+            assert!(Routed::parse(&buffer[..length]).is_none());
+        }
+        let mut unknown_kind = buffer.clone();
+        unknown_kind[0] = 2;
+        assert!(Routed::parse(&unknown_kind).is_none());
+        let mut invalid_destination = buffer;
+        invalid_destination[3] = 0xff;
+        assert!(Routed::parse(&invalid_destination).is_none());
+    }
+}
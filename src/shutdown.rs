@@ -0,0 +1,33 @@
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::transport;
+
+// Lets the background threads of a network sleep until their next round,
+// or until the network is closed, whichever comes first.
+#[derive(Default)]
+pub struct Shutdown {
+    closed: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl Shutdown {
+    pub fn close(&self) {
+        *transport::lock(&self.closed) = true;
+        self.changed.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *transport::lock(&self.closed)
+    }
+
+    // Returns true when the network was closed before the timeout passed.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let closed = transport::lock(&self.closed);
+        *self
+            .changed
+            .wait_timeout_while(closed, timeout, |closed| !*closed)
+            .unwrap_or_else(PoisonError::into_inner)
+            .0
+    }
+}
@@ -0,0 +1,337 @@
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{
+    ClientConfig, ClientConnection, Connection, DigitallySignedStruct, DistinguishedName,
+    ServerConfig, ServerConnection, SignatureScheme,
+};
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::transport::Stream;
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+// Certificates are not checked against any authority. Both sides present one
+// and the application decides whether to trust it from `Peer.certificate`.
+pub struct Tls {
+    server: Arc<ServerConfig>,
+    client: Arc<ClientConfig>,
+    certificate: CertificateDer<'static>,
+}
+
+impl Tls {
+    pub fn new(certfile: Option<&str>, keyfile: Option<&str>) -> io::Result<Self> {
+        let (certificates, key) = match (certfile, keyfile) {
+            (Some(certfile), Some(keyfile)) => (
+                CertificateDer::pem_file_iter(certfile)
+                    .and_then(Iterator::collect)
+                    .map_err(invalid_input)?,
+                PrivateKeyDer::from_pem_file(keyfile).map_err(invalid_input)?,
+            ),
+            (None, None) => self_signed()?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "certfile and keyfile must be given together",
+                ))
+            }
+        };
+        let certificate = certificates
+            .first()
+            .cloned()
+            .ok_or_else(|| invalid_input("certfile contains no certificates"))?;
+
+        let provider = Arc::new(ring::default_provider());
+        let verifier = Arc::new(AcceptAny(provider.clone()));
+
+        let server = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?
+            .with_client_cert_verifier(verifier.clone())
+            .with_single_cert(certificates.clone(), key.clone_key())
+            .map_err(invalid_input)?;
+
+        let client = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_client_auth_cert(certificates, key)
+            .map_err(invalid_input)?;
+
+        Ok(Self {
+            server: Arc::new(server),
+            client: Arc::new(client),
+            certificate,
+        })
+    }
+
+    pub fn certificate(&self) -> &[u8] {
+        &self.certificate
+    }
+
+    pub fn accept(&self, socket: &dyn Stream) -> io::Result<TlsStream> {
+        let connection = ServerConnection::new(self.server.clone()).map_err(invalid_data)?;
+        TlsStream::new(connection.into(), socket)
+    }
+
+    pub fn connect(&self, socket: &dyn Stream) -> io::Result<TlsStream> {
+        // The name is never verified, but rustls needs one to build the hello.
+        let ip = socket
+            .peer_addr()
+            .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |address| address.ip());
+        let connection =
+            ClientConnection::new(self.client.clone(), ServerName::IpAddress(ip.into()))
+                .map_err(invalid_data)?;
+        TlsStream::new(connection.into(), socket)
+    }
+}
+
+// A TLS session over a stream that is read and written from different
+// threads. Reads from the stream happen without holding the lock, so a
+// blocked reader never stalls writers.
+#[derive(Clone)]
+pub struct TlsStream {
+    session: Arc<Mutex<Session>>,
+}
+
+struct Session {
+    connection: Connection,
+    socket: Box<dyn Stream>,
+}
+
+impl TlsStream {
+    fn new(connection: Connection, socket: &dyn Stream) -> io::Result<Self> {
+        let stream = Self {
+            session: Arc::new(Mutex::new(Session {
+                connection,
+                socket: socket.try_clone()?,
+            })),
+        };
+        stream.lock().flush_tls()?;
+        Ok(stream)
+    }
+
+    pub fn peer_certificate(&self) -> Option<Vec<u8>> {
+        self.lock()
+            .connection
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .map(|certificate| certificate.to_vec())
+    }
+
+    pub fn reader(&self) -> io::Result<TlsReader> {
+        Ok(TlsReader {
+            stream: self.clone(),
+            socket: self.lock().socket.try_clone()?,
+            buffer: vec![0; BUFFER_SIZE],
+            start: 0,
+            end: 0,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Session> {
+        self.session
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl Session {
+    fn flush_tls(&mut self) -> io::Result<()> {
+        while self.connection.wants_write() {
+            self.connection.write_tls(&mut self.socket)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let mut session = self.lock();
+        let written = session.connection.writer().write(buffer)?;
+        session.flush_tls()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.lock();
+        session.connection.writer().flush()?;
+        session.flush_tls()
+    }
+}
+
+pub struct TlsReader {
+    stream: TlsStream,
+    socket: Box<dyn Stream>,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut session = self.stream.lock();
+                match session.connection.reader().read(output) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+            }
+
+            if self.start == self.end {
+                self.start = 0;
+                self.end = self.socket.read(&mut self.buffer)?;
+                if self.end == 0 {
+                    return Ok(0);
+                }
+            }
+
+            let mut session = self.stream.lock();
+            self.start += session
+                .connection
+                .read_tls(&mut &self.buffer[self.start..self.end])?;
+            session
+                .connection
+                .process_new_packets()
+                .map_err(invalid_data)?;
+            session.flush_tls()?;
+        }
+    }
+}
+
+pub fn fingerprint(certificate: &[u8]) -> String {
+    let digest = ::ring::digest::digest(&::ring::digest::SHA256, certificate);
+    digest
+        .as_ref()
+        .iter()
+        .fold(String::new(), |mut output, byte| {
+            if !output.is_empty() {
+                output.push(':');
+            }
+            let _ = write!(output, "{byte:02X}");
+            output
+        })
+}
+
+fn self_signed() -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let certified =
+        rcgen::generate_simple_self_signed(vec!["tknetwork".to_string()]).map_err(invalid_data)?;
+    let key = PrivateKeyDer::try_from(certified.key_pair.serialize_der()).map_err(invalid_data)?;
+    Ok((vec![certified.cert.der().clone()], key))
+}
+
+#[derive(Debug)]
+struct AcceptAny(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAny {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for AcceptAny {
+    // Clients without a certificate, such as browsers, are still accepted.
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn invalid_input<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error.to_string())
+}
+
+fn invalid_data<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
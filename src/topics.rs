@@ -0,0 +1,125 @@
+// Topics are words separated by dots, such as "board.1.draw". In patterns
+// "*" stands for exactly one word and "#" for any number of words,
+// including none.
+const SEPARATOR: char = '.';
+// Patterns come from peers, so how many and how long they can be is limited.
+pub const MAX_LEN: usize = 255;
+pub const MAX_SUBSCRIPTIONS: usize = 256;
+
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    let mut pattern: Vec<&str> = pattern.split(SEPARATOR).collect();
+    // A run of "#" matches the same as a single one.
+    pattern.dedup_by(|word, previous| *word == "#" && *previous == "#");
+    let topic: Vec<&str> = topic.split(SEPARATOR).collect();
+    matches_words(&pattern, &topic)
+}
+
+// Keeps, for every number of leading topic words, whether the pattern words
+// so far match them, which takes pattern × topic steps whatever the pattern.
+fn matches_words(pattern: &[&str], topic: &[&str]) -> bool {
+    let mut matched = vec![false; topic.len() + 1];
+    matched[0] = true;
+    for &word in pattern {
+        if word == "#" {
+            for count in 1..=topic.len() {
+                matched[count] |= matched[count - 1];
+            }
+        } else {
+            for count in (1..=topic.len()).rev() {
+                matched[count] = matched[count - 1] && (word == "*" || word == topic[count - 1]);
+            }
+            matched[0] = false;
+        }
+    }
+    matched[topic.len()]
+}
+
+pub fn valid_pattern(pattern: &str) -> bool {
+    pattern.len() <= MAX_LEN && !pattern.split(SEPARATOR).any(str::is_empty)
+}
+
+// Topics are published as they are, so they cannot hold wildcards.
+pub fn valid_topic(topic: &str) -> bool {
+    valid_pattern(topic)
+        && !topic
+            .split(SEPARATOR)
+            .any(|word| word == "*" || word == "#")
+}
+
+// Peers send the full list of patterns they subscribe to whenever it
+// changes.
+pub fn encode(patterns: &[&str]) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(patterns)
+}
+
+pub fn decode(body: &[u8]) -> Option<Vec<String>> {
+    let patterns: Vec<String> = serde_json::from_slice(body).ok()?;
+    let valid = patterns.len() <= MAX_SUBSCRIPTIONS
+        && patterns.iter().all(|pattern| valid_pattern(pattern));
+    valid.then_some(patterns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_and_wildcards() {
+        assert!(matches("chat.lobby", "chat.lobby"));
+        assert!(!matches("chat.lobby", "chat.other"));
+        assert!(matches("chat.*", "chat.lobby"));
+        assert!(!matches("chat.*", "chat"));
+        assert!(!matches("chat.*", "chat.lobby.x"));
+        assert!(matches("*.*", "a.b"));
+    }
+
+    #[test]
+    fn hash_matches_any_number_of_words() {
+        assert!(matches("#", "a"));
+        assert!(matches("#", "a.b.c"));
+        assert!(matches("board.1.#", "board.1"));
+        assert!(matches("board.1.#", "board.1.draw.line"));
+        assert!(!matches("board.1.#", "board.2.draw"));
+        assert!(matches("#.end", "end"));
+        assert!(matches("#.end", "a.b.end"));
+        assert!(!matches("#.end", "a.b.end.x"));
+        assert!(matches("a.#.*.z", "a.b.z"));
+        assert!(!matches("a.#.*.z", "a.z"));
+    }
+
+    #[test]
+    fn many_hashes_match_quickly() {
+        let pattern = ["#"; 64].join(".") + ".z";
+        let topic = ["w"; 64].join(".");
+        assert!(!matches(&pattern, &topic));
+        let pattern = ["#", "w"].repeat(32).join(".") + ".z";
+        assert!(!matches(&pattern, &topic));
+        assert!(matches(&pattern, &(topic + ".z")));
+    }
+
+    #[test]
+    fn patterns_and_topics() {
+        assert!(valid_pattern("a.*.#"));
+        assert!(!valid_pattern(""));
+        assert!(!valid_pattern("a..b"));
+        assert!(!valid_pattern(&"a".repeat(MAX_LEN + 1)));
+        assert!(valid_topic("a.b"));
+        assert!(!valid_topic("a.*"));
+        assert!(!valid_topic("#"));
+    }
+
+    #[test]
+    fn pattern_lists() {
+        let body = encode(&["a.#", "b.*"]).unwrap();
+        assert_eq!(
+            decode(&body),
+            Some(vec!["a.#".to_string(), "b.*".to_string()])
+        );
+        assert_eq!(decode(b"[]"), Some(Vec::new()));
+        assert_eq!(decode(b"{}"), None);
+        assert_eq!(decode(b"[1]"), None);
+        assert_eq!(decode(br#"["a..b"]"#), None);
+        let many = vec!["a"; MAX_SUBSCRIPTIONS + 1];
+        assert_eq!(decode(&encode(&many).unwrap()), None);
+    }
+}
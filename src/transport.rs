@@ -0,0 +1,524 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::address::{self, Address};
+
+// A connected byte stream that can be read and written from different
+// threads through clones of itself.
+pub trait Stream: Read + Write + Send {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>>;
+    fn shutdown(&self) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    // The IP address of the other side, used to send it datagrams. Only
+    // streams over IP have one.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+pub trait Listener: Send {
+    // Blocks until a new connection arrives, returning it with the address
+    // of the peer.
+    fn accept(&self) -> io::Result<(Box<dyn Stream>, Address)>;
+    fn local_addr(&self) -> io::Result<Address>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Transport {
+    Tcp,
+    Unix,
+    Memory,
+}
+
+impl Transport {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Tcp => "tcp",
+            Self::Unix => "unix",
+            Self::Memory => "memory",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Tcp, Self::Unix, Self::Memory]
+            .into_iter()
+            .find(|transport| transport.name() == name)
+    }
+
+    // For Unix sockets the address is a path and for in-memory channels it
+    // is any name, the port is only used by TCP.
+    pub fn bind(self, address: &str, port: u16) -> io::Result<Box<dyn Listener>> {
+        match self {
+            Self::Tcp => Ok(Box::new(address::bind_tcp(address, port)?)),
+            Self::Unix => unix::bind(address),
+            Self::Memory => Ok(Box::new(MemoryListener::bind(address)?)),
+        }
+    }
+
+    pub fn connect(
+        self,
+        address: &str,
+        port: u16,
+        timeout: Duration,
+    ) -> io::Result<(Box<dyn Stream>, Address)> {
+        match self {
+            Self::Tcp => connect_ip(address::resolve(address, port)?, timeout),
+            Self::Unix => Ok((unix::connect(address)?, Address::Unix(address.to_string()))),
+            Self::Memory => Ok((
+                Box::new(MemoryStream::connect(address)?),
+                Address::Memory(address.to_string()),
+            )),
+        }
+    }
+}
+
+// Connects to a peer again at the address it had.
+pub fn reconnect(address: &Address, timeout: Duration) -> io::Result<Box<dyn Stream>> {
+    match address {
+        Address::Ip(address) => Ok(connect_ip(*address, timeout)?.0),
+        Address::Unix(path) => unix::connect(path),
+        Address::Memory(name) => Ok(Box::new(MemoryStream::connect(name)?)),
+    }
+}
+
+// Unblocks a listener waiting in accept by connecting to it.
+pub fn wake(address: &Address) {
+    let address = match address {
+        Address::Ip(ip) => Address::Ip(address::loopback(*ip)),
+        _ => address.clone(),
+    };
+    reconnect(&address, Duration::from_secs(1)).ok();
+}
+
+pub fn connect_ip(
+    address: SocketAddr,
+    timeout: Duration,
+) -> io::Result<(Box<dyn Stream>, Address)> {
+    let socket = TcpStream::connect_timeout(&address, timeout)?;
+    let address = socket.peer_addr().unwrap_or(address);
+    Ok((Box::new(socket), Address::ip(address)))
+}
+
+impl<'source> FromPyObject<'source> for Transport {
+    fn extract(object: &'source PyAny) -> PyResult<Self> {
+        let name: &str = object.extract()?;
+        Self::from_name(name).ok_or_else(|| {
+            PyValueError::new_err(format!(
+                "Unknown transport '{name}', expected one of 'tcp', 'unix' or 'memory'"
+            ))
+        })
+    }
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(Self::try_clone(self)?))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        Self::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Self::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Self::set_write_timeout(self, timeout)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Self::peer_addr(self).ok()
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> io::Result<(Box<dyn Stream>, Address)> {
+        let (socket, address) = Self::accept(self)?;
+        Ok((Box::new(socket), Address::ip(address)))
+    }
+
+    fn local_addr(&self) -> io::Result<Address> {
+        Ok(Address::Ip(Self::local_addr(self)?))
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::{Listener, Stream};
+    use crate::address::Address;
+
+    use std::io;
+    use std::net::Shutdown;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::time::Duration;
+
+    pub fn bind(path: &str) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(UnixListener::bind(path)?))
+    }
+
+    pub fn connect(path: &str) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(UnixStream::connect(path)?))
+    }
+
+    impl Stream for UnixStream {
+        fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+            Ok(Box::new(Self::try_clone(self)?))
+        }
+
+        fn shutdown(&self) -> io::Result<()> {
+            Self::shutdown(self, Shutdown::Both)
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            Self::set_read_timeout(self, timeout)
+        }
+
+        fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            Self::set_write_timeout(self, timeout)
+        }
+    }
+
+    impl Listener for UnixListener {
+        fn accept(&self) -> io::Result<(Box<dyn Stream>, Address)> {
+            let (socket, _) = Self::accept(self)?;
+            // Clients rarely bind their socket to a path, so peers are named
+            // after the path they connected to.
+            Ok((Box::new(socket), Listener::local_addr(self)?))
+        }
+
+        fn local_addr(&self) -> io::Result<Address> {
+            let path = Self::local_addr(self)?
+                .as_pathname()
+                .map_or_else(String::new, |path| path.display().to_string());
+            Ok(Address::Unix(path))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod unix {
+    use super::{Listener, Stream};
+
+    use std::io;
+
+    pub fn bind(_path: &str) -> io::Result<Box<dyn Listener>> {
+        Err(unsupported())
+    }
+
+    pub fn connect(_path: &str) -> io::Result<Box<dyn Stream>> {
+        Err(unsupported())
+    }
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix sockets are not supported on this platform",
+        )
+    }
+}
+
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Names of the in-memory listeners in this process, so networks in the same
+// process can connect to each other without any sockets.
+fn listeners() -> MutexGuard<'static, HashMap<String, Sender<MemoryStream>>> {
+    static LISTENERS: OnceLock<Mutex<HashMap<String, Sender<MemoryStream>>>> = OnceLock::new();
+    lock(LISTENERS.get_or_init(Mutex::default))
+}
+
+pub struct MemoryListener {
+    name: String,
+    incoming: Receiver<MemoryStream>,
+    accepted: Cell<u64>,
+}
+
+impl MemoryListener {
+    fn bind(name: &str) -> io::Result<Self> {
+        let mut listeners = listeners();
+        if listeners.contains_key(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("Memory address '{name}' is already in use"),
+            ));
+        }
+        let (tx, rx) = channel();
+        listeners.insert(name.to_string(), tx);
+        Ok(Self {
+            name: name.to_string(),
+            incoming: rx,
+            accepted: Cell::new(0),
+        })
+    }
+}
+
+impl Listener for MemoryListener {
+    fn accept(&self) -> io::Result<(Box<dyn Stream>, Address)> {
+        let stream = self
+            .incoming
+            .recv()
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?;
+        let id = self.accepted.get() + 1;
+        self.accepted.set(id);
+        Ok((
+            Box::new(stream),
+            Address::Memory(format!("{}#{id}", self.name)),
+        ))
+    }
+
+    fn local_addr(&self) -> io::Result<Address> {
+        Ok(Address::Memory(self.name.clone()))
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        listeners().remove(&self.name);
+    }
+}
+
+// One direction of an in-memory stream.
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    buffer: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Pipe {
+    fn close(&self) {
+        lock(&self.state).closed = true;
+        self.ready.notify_all();
+    }
+}
+
+// Closes both directions once every clone of a stream is dropped, like
+// closing the last handle of a socket.
+struct Ends {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+}
+
+impl Drop for Ends {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+pub struct MemoryStream {
+    ends: Arc<Ends>,
+    timeout: Arc<Mutex<Option<Duration>>>,
+}
+
+impl MemoryStream {
+    fn connect(name: &str) -> io::Result<Self> {
+        let refused = || {
+            io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("Nothing is listening on memory address '{name}'"),
+            )
+        };
+        let tx = listeners().get(name).cloned().ok_or_else(refused)?;
+
+        let (client, server) = Self::pair();
+        tx.send(server).map_err(|_| refused())?;
+        Ok(client)
+    }
+
+    fn pair() -> (Self, Self) {
+        let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        let new = |incoming, outgoing| Self {
+            ends: Arc::new(Ends { incoming, outgoing }),
+            timeout: Arc::default(),
+        };
+        (new(a.clone(), b.clone()), new(b, a))
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        if output.is_empty() {
+            return Ok(0);
+        }
+        let deadline = lock(&self.timeout).map(|timeout| Instant::now() + timeout);
+        let pipe = &self.ends.incoming;
+
+        let mut state = lock(&pipe.state);
+        while state.buffer.is_empty() && !state.closed {
+            state = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline
+                        .checked_duration_since(Instant::now())
+                        .filter(|remaining| !remaining.is_zero())
+                        .ok_or_else(|| io::Error::from(io::ErrorKind::TimedOut))?;
+                    pipe.ready
+                        .wait_timeout(state, remaining)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => pipe
+                    .ready
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+
+        let length = output.len().min(state.buffer.len());
+        for (byte, output) in state.buffer.drain(..length).zip(output) {
+            *output = byte;
+        }
+        Ok(length)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let pipe = &self.ends.outgoing;
+        let mut state = lock(&pipe.state);
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.buffer.extend(buffer);
+        pipe.ready.notify_all();
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for MemoryStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(Self {
+            ends: self.ends.clone(),
+            timeout: self.timeout.clone(),
+        }))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.ends.incoming.close();
+        self.ends.outgoing.close();
+        Ok(())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *lock(&self.timeout) = timeout;
+        Ok(())
+    }
+
+    // Writes only append to the buffer of the other side, so they never
+    // block.
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    // Listener names are shared by the whole process, so every test uses
+    // its own.
+    fn connected(name: &str) -> (MemoryListener, MemoryStream, Box<dyn Stream>) {
+        let listener = MemoryListener::bind(name).unwrap();
+        let client = MemoryStream::connect(name).unwrap();
+        let (server, address) = listener.accept().unwrap();
+        assert_eq!(address, Address::Memory(format!("{name}#1")));
+        (listener, client, server)
+    }
+
+    fn read_all(stream: &mut dyn Stream) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    #[test]
+    fn streams_carry_bytes_both_ways() {
+        let (_listener, mut client, mut server) = connected("transport-both-ways");
+        client.write_all(b"ping").unwrap();
+        let mut buffer = [0; 4];
+        server.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+
+        let writer = thread::spawn(move || server.write_all(b"pong").unwrap());
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"pong");
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn reads_time_out() {
+        let (_listener, mut client, _server) = connected("transport-timeout");
+        client
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let started = Instant::now();
+        let error = client.read(&mut [0; 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn last_clone_dropped_closes_the_stream() {
+        let (_listener, client, mut server) = connected("transport-drop");
+        let clone = Stream::try_clone(&client).unwrap();
+        drop(client);
+        server.write_all(b"still open").unwrap();
+
+        drop(clone);
+        assert_eq!(read_all(server.as_mut()).unwrap(), b"");
+        let error = server.write_all(b"closed").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn shutdown_closes_both_sides() {
+        let (_listener, mut client, mut server) = connected("transport-shutdown");
+        client.write_all(b"before").unwrap();
+        Stream::shutdown(&client).unwrap();
+
+        // What was written before the shutdown can still be read.
+        assert_eq!(read_all(server.as_mut()).unwrap(), b"before");
+        for error in [client.write_all(b"x"), server.write_all(b"x")] {
+            assert_eq!(error.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        }
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn names_are_bound_once() {
+        let listener = MemoryListener::bind("transport-rebind").unwrap();
+        let error = MemoryListener::bind("transport-rebind").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+
+        drop(listener);
+        let error = MemoryStream::connect("transport-rebind").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        let listener = MemoryListener::bind("transport-rebind").unwrap();
+        assert_eq!(
+            listener.local_addr().unwrap(),
+            Address::Memory("transport-rebind".to_string())
+        );
+    }
+}
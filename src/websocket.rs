@@ -0,0 +1,303 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use std::io::{self, BufRead, Read};
+
+use crate::frame::MAX_FRAME_LEN;
+
+// Appended to the client's key before hashing, as fixed by RFC 6455.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_REQUEST_LEN: u64 = 8 * 1024;
+const MAX_CONTROL_LEN: usize = 125;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation = 0,
+    Text = 1,
+    Binary = 2,
+    Close = 8,
+    Ping = 9,
+    Pong = 10,
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Continuation),
+            1 => Ok(Self::Text),
+            2 => Ok(Self::Binary),
+            8 => Ok(Self::Close),
+            9 => Ok(Self::Ping),
+            10 => Ok(Self::Pong),
+            other => Err(other),
+        }
+    }
+}
+
+impl Opcode {
+    const fn is_control(self) -> bool {
+        matches!(self, Self::Close | Self::Ping | Self::Pong)
+    }
+}
+
+// Reads the HTTP upgrade request and returns the client's key.
+pub fn read_request<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut reader = reader.take(MAX_REQUEST_LEN);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.starts_with("GET ") || !line.trim_end().ends_with("HTTP/1.1") {
+        return Err(invalid_data("Not a WebSocket upgrade request"));
+    }
+
+    let (mut upgrade, mut version, mut key) = (false, false, None);
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid_data("Incomplete WebSocket upgrade request"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(invalid_data("Malformed WebSocket upgrade request"));
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "sec-websocket-version" => version = value == "13",
+            "sec-websocket-key" => key = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    match key {
+        Some(key) if upgrade && version => Ok(key),
+        _ => Err(invalid_data("Not a WebSocket upgrade request")),
+    }
+}
+
+pub fn response(key: &str) -> Vec<u8> {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{key}{GUID}").as_bytes(),
+    );
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        STANDARD.encode(digest)
+    )
+    .into_bytes()
+}
+
+// Frames sent by a server are never masked.
+pub fn encode(opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(payload.len() + 10);
+    buffer.push(0x80 | opcode as u8);
+    match payload.len() {
+        length @ 0..=125 => buffer.push(length as u8),
+        length @ 126..=0xFFFF => {
+            buffer.push(126);
+            buffer.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            buffer.push(127);
+            buffer.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    buffer.extend_from_slice(payload);
+    buffer
+}
+
+// Joins fragmented messages. Control frames may arrive between the
+// fragments and are returned as soon as they are read.
+#[derive(Default)]
+pub struct MessageReader {
+    partial: Option<(Opcode, Vec<u8>)>,
+}
+
+impl MessageReader {
+    pub fn read<R: Read>(&mut self, reader: &mut R) -> io::Result<(Opcode, Vec<u8>)> {
+        loop {
+            let (fin, opcode, payload) = read_frame(reader)?;
+
+            let (opcode, payload) = match opcode {
+                _ if opcode.is_control() => return Ok((opcode, payload)),
+                Opcode::Continuation => {
+                    let (opcode, mut buffer) = self
+                        .partial
+                        .take()
+                        .ok_or_else(|| invalid_data("Unexpected continuation frame"))?;
+                    if buffer.len() + payload.len() > MAX_FRAME_LEN {
+                        return Err(invalid_data("Frame exceeds maximum length"));
+                    }
+                    buffer.extend_from_slice(&payload);
+                    (opcode, buffer)
+                }
+                _ if self.partial.is_some() => {
+                    return Err(invalid_data("Expected a continuation frame"))
+                }
+                _ => (opcode, payload),
+            };
+
+            if fin {
+                return Ok((opcode, payload));
+            }
+            self.partial = Some((opcode, payload));
+        }
+    }
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<(bool, Opcode, Vec<u8>)> {
+    let mut header = [0; 2];
+    reader.read_exact(&mut header)?;
+
+    let fin = header[0] & 0x80 != 0;
+    if header[0] & 0x70 != 0 {
+        return Err(invalid_data("Unsupported WebSocket extension"));
+    }
+    let opcode =
+        Opcode::try_from(header[0] & 0x0F).map_err(|_| invalid_data("Unknown WebSocket opcode"))?;
+    // Clients must mask every frame they send.
+    if header[1] & 0x80 == 0 {
+        return Err(invalid_data("Unmasked WebSocket frame"));
+    }
+
+    let length = match header[1] & 0x7F {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length)?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0; 8];
+            reader.read_exact(&mut length)?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+    if length > MAX_FRAME_LEN as u64 {
+        return Err(invalid_data("Frame exceeds maximum length"));
+    }
+    if opcode.is_control() && (!fin || length as usize > MAX_CONTROL_LEN) {
+        return Err(invalid_data("Malformed WebSocket control frame"));
+    }
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    for (byte, mask) in payload.iter_mut().zip(mask.iter().cycle()) {
+        *byte ^= mask;
+    }
+    Ok((fin, opcode, payload))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+    fn request(headers: &str) -> String {
+        format!("GET / HTTP/1.1\r\nHost: example\r\n{headers}\r\n")
+    }
+
+    // Builds a frame the way a client sends it, masked.
+    fn client_frame(fin: bool, opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+        let mut frame = encode(opcode, payload);
+        let start = frame.len() - payload.len();
+        if !fin {
+            frame[0] &= 0x7F;
+        }
+        frame[1] |= 0x80;
+        let mask = [1, 2, 3, 4];
+        for (byte, mask) in frame[start..].iter_mut().zip(mask.iter().cycle()) {
+            *byte ^= mask;
+        }
+        frame.splice(start..start, mask);
+        frame
+    }
+
+    #[test]
+    fn upgrade_requests() {
+        let valid = request(&format!(
+            "Upgrade: WebSocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: {KEY}\r\n"
+        ));
+        assert_eq!(read_request(&mut valid.as_bytes()).unwrap(), KEY);
+        let response = String::from_utf8(response(KEY)).unwrap();
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        for invalid in [
+            request(&format!(
+                "Upgrade: websocket\r\nSec-WebSocket-Key: {KEY}\r\n"
+            )),
+            request("Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n"),
+            request("Malformed\r\n"),
+            "POST / HTTP/1.1\r\n\r\n".to_string(),
+            "GET / HTTP/1.1\r\nUpgrade: websocket\r\n".to_string(),
+            format!(
+                "GET / HTTP/1.1\r\nX: {}\r\n\r\n",
+                "a".repeat(MAX_REQUEST_LEN as usize)
+            ),
+        ] {
+            assert!(read_request(&mut invalid.as_bytes()).is_err());
+        }
+    }
+
+    #[test]
+    fn fragmented_messages() {
+        let mut stream = client_frame(false, Opcode::Text, b"hel");
+        stream.extend(client_frame(true, Opcode::Ping, b"ping"));
+        stream.extend(client_frame(true, Opcode::Continuation, b"lo"));
+        stream.extend(client_frame(true, Opcode::Binary, &[7; 300]));
+        let (mut stream, mut reader) = (&stream[..], MessageReader::default());
+
+        assert_eq!(
+            reader.read(&mut stream).unwrap(),
+            (Opcode::Ping, b"ping".to_vec())
+        );
+        assert_eq!(
+            reader.read(&mut stream).unwrap(),
+            (Opcode::Text, b"hello".to_vec())
+        );
+        assert_eq!(
+            reader.read(&mut stream).unwrap(),
+            (Opcode::Binary, vec![7; 300])
+        );
+    }
+
+    #[test]
+    fn malformed_frames() {
+        let read = |frame: &[u8]| MessageReader::default().read(&mut &frame[..]);
+
+        assert!(read(&encode(Opcode::Text, b"unmasked")).is_err());
+        assert!(read(&client_frame(true, Opcode::Continuation, b"first")).is_err());
+        assert!(read(&client_frame(false, Opcode::Ping, b"")).is_err());
+        assert!(read(&client_frame(true, Opcode::Ping, &[0; MAX_CONTROL_LEN + 1])).is_err());
+        assert!(read(&client_frame(true, Opcode::Text, b"short")[..8]).is_err());
+
+        let mut unknown = client_frame(true, Opcode::Text, b"");
+        unknown[0] = 0x83;
+        assert!(read(&unknown).is_err());
+        let mut extension = client_frame(true, Opcode::Text, b"");
+        extension[0] |= 0x40;
+        assert!(read(&extension).is_err());
+
+        let mut too_long = vec![0x82, 0x80 | 127];
+        too_long.extend_from_slice(&(MAX_FRAME_LEN as u64 + 1).to_be_bytes());
+        assert!(read(&too_long).is_err());
+
+        let mut interrupted = client_frame(false, Opcode::Text, b"a");
+        interrupted.extend(client_frame(true, Opcode::Text, b"b"));
+        assert!(read(&interrupted).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use crate::transport::Stream;
+
+enum Outgoing {
+    Frame(Vec<u8>),
+    // Shuts the connection down once everything queued before it is written.
+    Shutdown,
+}
+
+// Writes the frames queued for a connection on a thread of its own, so a
+// peer that stops reading never blocks the thread that emits, which may
+// hold the GIL. Writes fail once the socket's write timeout passes, which
+// shuts the connection down and ends the thread.
+pub struct Writer {
+    queue: Sender<Outgoing>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl Writer {
+    pub fn spawn(mut writer: Box<dyn Write + Send>, socket: Box<dyn Stream>) -> io::Result<Self> {
+        let (queue, rx) = channel();
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let flag = timed_out.clone();
+        thread::Builder::new()
+            .name("writer".to_string())
+            .spawn(move || {
+                for outgoing in rx {
+                    let Outgoing::Frame(buffer) = outgoing else {
+                        break;
+                    };
+                    if let Err(e) = writer.write_all(&buffer) {
+                        let timeout = matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        );
+                        flag.store(timeout, Ordering::Relaxed);
+                        break;
+                    }
+                }
+                // Ends the listen thread of the connection as well.
+                socket.shutdown().ok();
+            })?;
+        Ok(Self { queue, timed_out })
+    }
+
+    pub fn write(&self, buffer: &[u8]) -> io::Result<()> {
+        self.queue
+            .send(Outgoing::Frame(buffer.to_vec()))
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+
+    pub fn shutdown(&self) {
+        self.queue.send(Outgoing::Shutdown).ok();
+    }
+
+    // Whether the connection was shut down because the peer stopped
+    // reading what was written to it.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+}